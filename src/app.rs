@@ -1,16 +1,48 @@
-use eyre::{eyre, Error, WrapErr as _};
+use eyre::{bail, eyre, Error, WrapErr as _};
 use futures::{
     future::{FutureExt, Shared},
+    sink::SinkExt as _,
     stream::{StreamExt as _, TryStreamExt as _},
 };
+use indexmap::IndexMap;
 use listenfd::ListenFd;
-use std::{cell::RefCell, future::Future, path::PathBuf, pin::Pin, rc::Rc};
+use std::{
+    cell::{Cell, RefCell},
+    collections::hash_map::RandomState,
+    future::Future,
+    hash::{BuildHasher as _, Hasher as _},
+    io::Read as _,
+    path::{Path, PathBuf},
+    pin::{pin, Pin},
+    rc::Rc,
+    time::{Duration, Instant, SystemTime},
+};
+use tokio::{
+    net::UnixStream,
+    signal::unix::{signal, SignalKind},
+};
+use tokio_stream::wrappers::SignalStream;
+use tokio_util::codec::Framed;
 use tracing::Instrument;
 
 use crate::{
+    audit::{self, AuditLog},
+    capture::{self, Capture, Direction},
     client::Client,
+    config::{Config, LogFormat, Logging, TimeoutOverrides},
+    decode::{self, Decoded},
+    discovery,
     error::ErrorExt as _,
-    net, server,
+    keystore::KEYSTORE_PATH,
+    metrics::{self, Family, Kind, Metrics, Sample},
+    monitor::{EventKind, Events},
+    net,
+    packets::{
+        self, Certificate, Codec, DaemonStatus, Frame, UpstreamDetail, UpstreamDetails,
+        UpstreamStatus,
+    },
+    server::{self, Socket},
+    top,
     upstreams::{Upstream, Upstreams},
 };
 
@@ -23,18 +55,44 @@ pub(crate) enum App {
         #[command(subcommand)]
         list: List,
     },
+    Audit(Audit),
+    Status(Status),
+    Monitor(Monitor),
+    Top(Top),
+    Replay(Replay),
+    Decode(Decode),
 }
 
 /// Start up as a daemon
 #[derive(Debug, clap::Parser)]
 pub(crate) struct Daemon {
-    #[arg(long, short('a'), required_unless_present = "systemd")]
+    /// Required unless `--systemd` is passed or `daemon.bind-address` is set in the config
+    #[arg(long, short('a'))]
     bind_address: Option<PathBuf>,
     #[arg(long, short, conflicts_with = "bind_address")]
     systemd: bool,
+    /// Automatically add forwarded agents that `sshd` creates in `/tmp/ssh-*/agent.*` as upstreams,
+    /// and remove them again when they disappear
+    #[arg(long)]
+    discover: bool,
+    /// Serve the management extensions (adding and listing upstreams) only on a separate socket
+    /// at this path, which only accepts connections from the same user
+    #[arg(long)]
+    control_address: Option<PathBuf>,
+    /// How to write logs [default: logging.format from the config, or compact]
+    #[arg(long, value_enum)]
+    log_format: Option<LogFormat>,
+    /// Never log sign payloads, signatures or key blobs, even at debug level; keys are logged by
+    /// fingerprint instead
+    #[arg(long)]
+    redact_logs: bool,
+    /// Read the config from this file [default: $XDG_CONFIG_HOME/sshagmux/config.toml]
+    #[arg(long, short)]
+    config: Option<PathBuf>,
 }
 
-/// Connect to the instance at `SSH_AUTH_SOCK` and tell it to add `path` as an upstream server
+/// Connect to the instance at `SSHAGMUX_CONTROL_SOCK` (or `SSH_AUTH_SOCK` if unset) and tell it to
+/// add `path` as an upstream server
 #[derive(Debug, clap::Parser)]
 pub(crate) struct AddUpstream {
     path: String,
@@ -42,34 +100,405 @@ pub(crate) struct AddUpstream {
     /// server so only the latest (lowest in `list upstreams`) will have it forwarded
     #[clap(long)]
     forward_adds: bool,
+    /// Override the daemon's timeout for identities requests to this server
+    #[clap(long, value_parser = humantime::parse_duration)]
+    identities_timeout: Option<Duration>,
+    /// Override the daemon's timeout for sign requests to this server
+    #[clap(long, value_parser = humantime::parse_duration)]
+    sign_timeout: Option<Duration>,
+    /// Override the daemon's timeout for sign requests using security keys to this server
+    #[clap(long, value_parser = humantime::parse_duration)]
+    sign_sk_timeout: Option<Duration>,
+    /// Override the daemon's timeout for forwarded add/remove identity requests to this server
+    #[clap(long, value_parser = humantime::parse_duration)]
+    management_timeout: Option<Duration>,
 }
 
 /// Connect to the instance at `SSH_AUTH_SOCK` and list items from it
 #[derive(Debug, clap::Parser)]
 pub(crate) enum List {
-    /// List identities (like `ssh-add -l`)
-    Identities,
-    /// List upstreams
+    /// List identities (like `ssh-add -l`), along with the details of certificates
+    Identities {
+        /// Also list certificates the daemon hides because they're expired or not yet valid, from
+        /// the identities it has cached from its upstreams, using `SSHAGMUX_CONTROL_SOCK` if set
+        #[arg(long)]
+        all: bool,
+    },
+    /// List upstreams, using `SSHAGMUX_CONTROL_SOCK` if set
     Upstreams,
 }
 
+/// Show records from the daemon's audit log, oldest first
+#[derive(Debug, clap::Parser)]
+pub(crate) struct Audit {
+    /// Only show records about this key, by its `SHA256:...` fingerprint
+    #[arg(long, short)]
+    key: Option<String>,
+    /// Only show records about this upstream
+    #[arg(long, short)]
+    upstream: Option<String>,
+    /// Only show records after this time, either a timestamp (`2024-01-31T12:00:00Z`) or how long
+    /// ago (`2h`)
+    #[arg(long, value_parser = parse_time)]
+    since: Option<SystemTime>,
+    /// Only show records before this time, in the same format as `--since`
+    #[arg(long, value_parser = parse_time)]
+    until: Option<SystemTime>,
+    /// Print the matching records as JSON lines
+    #[arg(long)]
+    json: bool,
+    /// Read this audit log instead of the `audit.path` from the config
+    #[arg(long, short)]
+    file: Option<PathBuf>,
+    /// Read the config from this file [default: $XDG_CONFIG_HOME/sshagmux/config.toml]
+    #[arg(long, short)]
+    config: Option<PathBuf>,
+}
+
+/// Connect to the instance at `SSHAGMUX_CONTROL_SOCK` (or `SSH_AUTH_SOCK` if unset) and show its
+/// status, exits with 2 if it is locked, has no upstreams or has unhealthy upstreams
+#[derive(Debug, clap::Parser)]
+pub(crate) struct Status {
+    /// Also show statistics about requests and upstreams
+    #[arg(long, short)]
+    stats: bool,
+}
+
+/// Connect to the instance at `SSHAGMUX_CONTROL_SOCK` (or `SSH_AUTH_SOCK` if unset) and print its
+/// events as they happen, such as connections, requests and which upstreams sign requests are
+/// waiting on
+#[derive(Debug, clap::Parser)]
+pub(crate) struct Monitor {}
+
+/// Connect to the instance at `SSHAGMUX_CONTROL_SOCK` (or `SSH_AUTH_SOCK` if unset) and show a live
+/// dashboard of its upstreams, their identities, in-flight requests and recent signatures, from
+/// which upstreams can be removed or preferred and the daemon locked
+#[derive(Debug, clap::Parser)]
+pub(crate) struct Top {}
+
+/// Print agent protocol messages in a readable form: their types, keys, extension names and
+/// contents
+#[derive(Debug, clap::Parser)]
+pub(crate) struct Decode {
+    /// Read the messages from this file [default: stdin]
+    file: Option<PathBuf>,
+    /// The input is hex rather than raw bytes, whitespace is ignored
+    #[arg(long, conflicts_with = "capture")]
+    hex: bool,
+    /// The input is a capture written by the daemon, see `capture.path` in the config
+    #[arg(long, requires = "file")]
+    capture: bool,
+}
+
+/// Send the requests from a capture to an agent one connection at a time, and compare its responses
+/// with the captured ones, exits with 2 if any differ
+#[derive(Debug, clap::Parser)]
+pub(crate) struct Replay {
+    /// A capture written by the daemon, see `capture.path` in the config
+    file: PathBuf,
+    /// Replay the requests the daemon sent to this upstream, rather than those clients sent to the
+    /// daemon
+    #[arg(long, short)]
+    upstream: Option<String>,
+    /// Only replay the requests from this client connection
+    #[arg(long, conflicts_with = "upstream")]
+    connection: Option<u64>,
+    /// Send the requests to this socket [default: the upstream when `--upstream` is passed,
+    /// otherwise `SSH_AUTH_SOCK`]
+    #[arg(long, short)]
+    to: Option<PathBuf>,
+    /// How long to wait for each response
+    #[arg(long, value_parser = humantime::parse_duration, default_value = "60s")]
+    timeout: Duration,
+}
+
 pub(crate) struct Context {
     pub(crate) path: RefCell<Option<String>>,
+    pub(crate) control_path: RefCell<Option<String>>,
+    config: RefCell<Rc<Config>>,
     pub(crate) upstreams: Upstreams,
+    pub(crate) audit: Rc<AuditLog>,
+    pub(crate) metrics: Rc<Metrics>,
+    pub(crate) events: Rc<Events>,
+    pub(crate) capture: Rc<Capture>,
+    pub(crate) connections: RefCell<IndexMap<u64, Connection>>,
+    pub(crate) started: Instant,
+    /// Identifies this daemon to `loop-check@nemo157.com`
+    pub(crate) instance: u64,
     pub(crate) shutdown: Shared<Pin<Box<dyn Future<Output = ()>>>>,
 }
 
+/// A downstream client currently connected to the daemon
+#[derive(Debug)]
+pub(crate) struct Connection {
+    pub(crate) opened: Instant,
+    pub(crate) peer: net::Peer,
+    pub(crate) socket: Socket,
+}
+
 impl Context {
-    pub(crate) fn new(shutdown: impl Future<Output = ()> + 'static) -> Self {
+    pub(crate) fn new(shutdown: impl Future<Output = ()> + 'static, config: Config) -> Self {
+        let audit = Rc::new(AuditLog::default());
+        let metrics = Rc::new(Metrics::default());
+        let events = Rc::new(Events::default());
+        let capture = Rc::new(Capture::default());
         Self {
             path: RefCell::new(None),
-            upstreams: Upstreams::new(),
+            control_path: RefCell::new(None),
+            config: RefCell::new(Rc::new(config)),
+            upstreams: Upstreams::new(
+                audit.clone(),
+                metrics.clone(),
+                events.clone(),
+                capture.clone(),
+            ),
+            audit,
+            metrics,
+            events,
+            capture,
+            connections: RefCell::new(IndexMap::new()),
+            started: Instant::now(),
+            instance: RandomState::new().build_hasher().finish(),
             shutdown: Box::pin(shutdown).boxed_local().shared(),
         }
     }
+
+    pub(crate) fn config(&self) -> Rc<Config> {
+        self.config.borrow().clone()
+    }
+
+    /// Re-reads the config and applies it to the upstreams, existing connections are unaffected
+    #[culpa::throws]
+    pub(crate) fn reload(&self, path: Option<&Path>) {
+        let config = Rc::new(Config::load(path)?);
+        let old = self.config.replace(config.clone());
+
+        if config.daemon != old.daemon {
+            tracing::warn!("changes to daemon settings require a restart");
+        }
+        if config.shutdown != old.shutdown {
+            tracing::warn!("changes to shutdown settings require a restart");
+        }
+        if config.logging != old.logging {
+            tracing::warn!("changes to logging settings require a restart");
+        }
+        if config.metrics != old.metrics {
+            tracing::warn!("changes to metrics settings require a restart");
+        }
+
+        self.audit
+            .configure(config.audit.path.as_deref())
+            .log_warn();
+        self.capture
+            .configure(config.capture.path.as_deref())
+            .log_warn();
+        self.upstreams.configure(&config);
+    }
+
+    /// The metrics, along with gauges of the current state
+    pub(crate) fn stats(&self) -> Vec<Family> {
+        let gauge = |name: &str, help: &str, samples| Family {
+            name: name.to_owned(),
+            help: help.to_owned(),
+            kind: Kind::Gauge,
+            samples,
+        };
+        let mut families = self.metrics.families();
+        families.push(gauge(
+            "sshagmux_connections",
+            "Client connections currently open",
+            vec![Sample {
+                suffix: String::new(),
+                labels: Vec::new(),
+                value: self.connections.borrow().len() as f64,
+            }],
+        ));
+        families.push(gauge(
+            "sshagmux_upstream_healthy",
+            "Whether the last request to each upstream succeeded",
+            self.upstreams
+                .health()
+                .into_iter()
+                .map(|(client, health)| Sample {
+                    suffix: String::new(),
+                    labels: vec![("upstream".to_owned(), client.path.to_string())],
+                    value: if health.is_healthy() { 1.0 } else { 0.0 },
+                })
+                .collect(),
+        ));
+        families
+    }
+
+    /// A summary of the daemon's health
+    pub(crate) fn status(&self) -> DaemonStatus {
+        let now = Instant::now();
+        DaemonStatus {
+            version: env!("CARGO_PKG_VERSION").to_owned(),
+            uptime: now - self.started,
+            path: self.path.borrow().clone(),
+            connections: u32::try_from(self.connections.borrow().len()).unwrap_or(u32::MAX),
+            locked: self.upstreams.is_locked(),
+            upstreams: self
+                .upstreams
+                .health()
+                .into_iter()
+                .map(|(client, health)| UpstreamStatus {
+                    path: client.path.clone(),
+                    forward_adds: client.forward_adds,
+                    healthy: health.is_healthy(),
+                    last_success: health.last_success.map(|at| now - at),
+                    last_failure: health
+                        .last_failure
+                        .as_ref()
+                        .map(|(at, error)| (now - *at, error.to_string())),
+                    cached: health.identities.as_ref().map(|(at, keys)| {
+                        (u32::try_from(keys.len()).unwrap_or(u32::MAX), now - *at)
+                    }),
+                })
+                .collect(),
+        }
+    }
+
+    pub(crate) fn upstream_details(&self) -> UpstreamDetails {
+        UpstreamDetails {
+            preferred: self.upstreams.preferred(),
+            upstreams: self
+                .upstreams
+                .health()
+                .into_iter()
+                .map(|(client, health)| UpstreamDetail {
+                    path: client.path.clone(),
+                    latency: health.latency,
+                    identities: health.identities.map(|(_, keys)| keys.to_vec()),
+                })
+                .collect(),
+        }
+    }
+
+    /// Logs everything we know about the current state of the daemon
+    pub(crate) fn dump_state(&self) {
+        let now = Instant::now();
+        let ago = |instant: Instant| {
+            humantime::format_duration(Duration::from_secs(
+                now.saturating_duration_since(instant).as_secs(),
+            ))
+            .to_string()
+                + " ago"
+        };
+
+        let upstreams = self.upstreams.health();
+        let preferred = self.upstreams.preferred();
+        let connections = self.connections.borrow();
+        tracing::info!(
+            path = self.path.borrow().as_deref(),
+            upstreams = upstreams.len(),
+            connections = connections.len(),
+            "state dump"
+        );
+
+        for (client, health) in upstreams {
+            tracing::info!(
+                path = %client.path,
+                client.forward_adds,
+                client.persistent,
+                preferred = Some(&client.path) == preferred.as_ref(),
+                healthy = health.is_healthy(),
+                last_success = health.last_success.map(ago),
+                last_failure = health.last_failure.as_ref().map(|(at, _)| ago(*at)),
+                error = health.last_failure.as_ref().map(|(_, e)| e.as_ref()),
+                "upstream"
+            );
+            if let Some((updated, identities)) = &health.identities {
+                for key in identities.iter() {
+                    tracing::info!(
+                        upstream = %client.path,
+                        fingerprint = key.fingerprint(),
+                        comment = %key.comment(),
+                        updated = ago(*updated),
+                        "cached identity"
+                    );
+                }
+            }
+        }
+
+        for (id, connection) in connections.iter() {
+            tracing::info!(
+                id,
+                opened = ago(connection.opened),
+                ?connection.socket,
+                connection.peer.uid,
+                connection.peer.pid,
+                exe = connection
+                    .peer
+                    .exe
+                    .as_ref()
+                    .map(|exe| exe.display().to_string()),
+                "connection"
+            );
+        }
+    }
+
+    /// Tests that `upstream` is a working agent before adding it
+    #[culpa::throws]
+    pub(crate) async fn add_upstream(&self, upstream: Upstream) {
+        upstream.timeouts.validate()?;
+        let client = Client {
+            timeouts: upstream.timeouts.apply(self.config().timeouts),
+            ..Client::from(upstream)
+        };
+        if Some(client.path.as_ref()) == self.path.borrow().as_deref()
+            || Some(client.path.as_ref()) == self.control_path.borrow().as_deref()
+        {
+            bail!("attempted to add self as upstream");
+        }
+        if client.path.as_ref() == KEYSTORE_PATH {
+            bail!("attempted to replace the keystore");
+        }
+        let client = Client {
+            socket: Some(
+                net::verify_socket(Path::new(client.path.as_ref()))
+                    .context("refusing to add upstream")?,
+            ),
+            ..client
+        };
+        if client
+            .is_instance(self.instance)
+            .await
+            .context("failed to test connection")?
+        {
+            bail!("{:?} leads back to this daemon", client.path);
+        }
+        client
+            .request_identities()
+            .await
+            .context("failed to test connection")?;
+        self.upstreams.add(client).await;
+    }
 }
 
 impl App {
+    /// Only the daemon is configurable, everything else uses the defaults
+    #[culpa::throws]
+    pub(crate) fn config(&self) -> Config {
+        match self {
+            Self::Daemon(daemon) => Config::load(daemon.config.as_deref())?,
+            Self::Audit(audit) => Config::load(audit.config.as_deref())?,
+            _ => Config::default(),
+        }
+    }
+
+    /// The logging settings from `config`, overridden by the daemon's flags
+    pub(crate) fn logging(&self, config: &Config) -> Logging {
+        let mut logging = config.logging.clone();
+        if let Self::Daemon(daemon) = self {
+            if let Some(format) = daemon.log_format {
+                logging.format = format;
+            }
+            logging.redact |= daemon.redact_logs;
+        }
+        logging
+    }
+
     #[culpa::throws]
     pub(crate) async fn run(self, context: Rc<Context>) {
         tracing::debug!(%self, "starting app");
@@ -78,6 +507,12 @@ impl App {
             Self::Daemon(daemon) => daemon.run(context).await?,
             Self::AddUpstream(add_upstream) => add_upstream.run().await?,
             Self::List { list } => list.run().await?,
+            Self::Audit(audit) => audit.run(&context.config())?,
+            Self::Status(status) => status.run().await?,
+            Self::Monitor(monitor) => monitor.run(&context).await?,
+            Self::Top(top) => top.run(&context).await?,
+            Self::Replay(replay) => replay.run().await?,
+            Self::Decode(decode) => decode.run()?,
         }
     }
 }
@@ -93,7 +528,14 @@ impl Daemon {
             listener.set_nonblocking(true)?;
             net::UnixListener::from_std(listener, false)?
         } else {
-            let bind_address = self.bind_address.unwrap();
+            let Some(bind_address) = self
+                .bind_address
+                .as_ref()
+                .cloned()
+                .or_else(|| context.config().daemon.bind_address.clone())
+            else {
+                bail!("one of --bind-address, --systemd or daemon.bind-address in the config is required");
+            };
             net::UnixListener::bind(bind_address)?
         };
 
@@ -106,53 +548,310 @@ impl Daemon {
         }
         *context.path.borrow_mut() = path;
 
-        let mut next_id = 0;
-        listener
-            .incoming()
-            .take_until(context.shutdown.clone())
-            .map_err(|e| e.wrap_err("failed to accept connection"))
-            .try_for_each_concurrent(None, |(stream, _addr)| {
-                let connection_id = next_id;
-                next_id += 1;
-                let context = context.clone();
-                async move {
-                    if let Err(e) = server::handle(stream, context).await {
-                        tracing::warn!("{e:?}");
-                    }
-                    Ok(())
-                }
-                .instrument(tracing::info_span!("connection", connection_id))
-            })
-            .await?;
+        let control_address = self
+            .control_address
+            .clone()
+            .or_else(|| context.config().daemon.control_address.clone());
+        let control_listener = match &control_address {
+            Some(control_address) => {
+                let listener = net::UnixListener::bind_private(control_address)?;
+                tracing::info!("control socket bound to {}", control_address.display());
+                *context.control_path.borrow_mut() = control_address.to_str().map(|s| s.to_owned());
+                Some(listener)
+            }
+            None => None,
+        };
+        let main_socket = if control_listener.is_some() {
+            Socket::Agent
+        } else {
+            Socket::Combined
+        };
+
+        context
+            .audit
+            .configure(context.config().audit.path.as_deref())?;
+        context
+            .capture
+            .configure(context.config().capture.path.as_deref())?;
+        context.upstreams.configure(&context.config());
+
+        let discovery = async {
+            if self.discover || context.config().daemon.discover {
+                discovery::run(context.clone())
+                    .await
+                    .context("agent discovery failed")
+                    .log_err();
+            }
+        };
+
+        let next_id = Cell::new(0);
+        let connections = serve(&listener, &context, &next_id, main_socket);
+        let control_connections = async {
+            match &control_listener {
+                Some(listener) => serve(listener, &context, &next_id, Socket::Control).await,
+                None => Ok(()),
+            }
+        };
+
+        let signals = async {
+            self.handle_signals(&context)
+                .await
+                .context("signal handling failed")
+                .log_err();
+        };
+
+        let metrics = async {
+            if let Some(address) = &context.config().metrics.listen {
+                metrics::serve(address, context.clone())
+                    .await
+                    .context("metrics server failed")
+                    .log_err();
+            }
+        };
+
+        let (connections, control_connections, (), (), ()) = futures::future::join5(
+            connections,
+            control_connections,
+            discovery,
+            signals,
+            metrics,
+        )
+        .await;
+        connections?;
+        control_connections?;
 
         listener
             .close()
             .context("could not close unix listener")
             .log_warn();
+        if let Some(mut listener) = control_listener {
+            listener
+                .close()
+                .context("could not close control listener")
+                .log_warn();
+        }
+    }
+
+    /// Reloads the config on SIGHUP, and dumps the current state to the log on SIGUSR1
+    #[culpa::throws]
+    async fn handle_signals(&self, context: &Context) {
+        enum Signal {
+            Hangup,
+            User1,
+        }
+
+        let hangups = SignalStream::new(signal(SignalKind::hangup())?).map(|()| Signal::Hangup);
+        let user1s =
+            SignalStream::new(signal(SignalKind::user_defined1())?).map(|()| Signal::User1);
+
+        let mut signals =
+            pin!(futures::stream::select(hangups, user1s).take_until(context.shutdown.clone()));
+
+        while let Some(signal) = signals.next().await {
+            match signal {
+                Signal::Hangup => {
+                    tracing::info!("received SIGHUP, reloading config");
+                    context
+                        .reload(self.config.as_deref())
+                        .context("failed to reload config, keeping the current config")
+                        .log_warn();
+                }
+                Signal::User1 => {
+                    tracing::info!("received SIGUSR1, dumping state");
+                    context.dump_state();
+                }
+            }
+        }
     }
 }
 
+fn print_certificate(certificate: &Certificate, now: SystemTime) {
+    println!(
+        "    {} certificate {:?} for key {} signed by CA {}",
+        if certificate.host { "host" } else { "user" },
+        certificate.key_id,
+        packets::fingerprint(&certificate.key),
+        certificate.ca_fingerprint
+    );
+    if certificate.principals.is_empty() {
+        println!("    valid for any principal");
+    } else {
+        println!("    principals {}", certificate.principals.join(", "));
+    }
+    let from = certificate.valid_from();
+    match certificate.valid_to() {
+        Some(to) => println!(
+            "    valid from {from} to {to}, {}",
+            certificate.validity(now)
+        ),
+        None => println!(
+            "    valid from {from} forever, {}",
+            certificate.validity(now)
+        ),
+    }
+}
+
+/// The socket to send management extensions to, the daemon's control socket if it has one
+#[culpa::throws]
+fn control_socket() -> String {
+    match std::env::var("SSHAGMUX_CONTROL_SOCK") {
+        Ok(path) if !path.is_empty() => path,
+        _ => std::env::var("SSH_AUTH_SOCK")
+            .context("neither SSHAGMUX_CONTROL_SOCK nor SSH_AUTH_SOCK is set")?,
+    }
+}
+
+/// Accepts connections on `listener` until shutdown, handling each concurrently
+#[culpa::throws]
+async fn serve(
+    listener: &net::UnixListener,
+    context: &Rc<Context>,
+    next_id: &Cell<u64>,
+    socket: Socket,
+) {
+    listener
+        .incoming()
+        .take_until(context.shutdown.clone())
+        .map_err(|e| e.wrap_err("failed to accept connection"))
+        .try_for_each_concurrent(None, |(stream, _addr)| {
+            let connection_id = next_id.get();
+            next_id.set(connection_id + 1);
+            let context = context.clone();
+            async move {
+                let peer = match net::Peer::of(&stream) {
+                    Ok(peer) => peer,
+                    Err(e) => {
+                        tracing::warn!(
+                            "rejecting connection, failed to get peer credentials: {e:?}"
+                        );
+                        return Ok(());
+                    }
+                };
+                let span = tracing::Span::current();
+                span.record("uid", peer.uid);
+                span.record("pid", peer.pid);
+                span.record(
+                    "exe",
+                    peer.exe
+                        .as_ref()
+                        .map(|exe| tracing::field::display(exe.display())),
+                );
+                let allowed = match socket {
+                    // The control socket can redirect sign requests, so is never shared
+                    Socket::Control => peer.uid == nix::unistd::geteuid().as_raw(),
+                    Socket::Agent | Socket::Combined => context.config().access.allows(peer.uid),
+                };
+                if !allowed {
+                    tracing::warn!("rejecting connection from disallowed user");
+                    return Ok(());
+                }
+
+                context.connections.borrow_mut().insert(
+                    connection_id,
+                    Connection {
+                        opened: Instant::now(),
+                        peer: peer.clone(),
+                        socket,
+                    },
+                );
+                context.events.emit(EventKind::ConnectionOpened {
+                    connection: connection_id,
+                    uid: peer.uid,
+                    pid: peer.pid,
+                    exe: peer.exe.as_ref().map(|exe| exe.display().to_string()),
+                });
+                if let Err(e) =
+                    server::handle(stream, context.clone(), socket, connection_id, peer).await
+                {
+                    tracing::warn!("{e:?}");
+                }
+                context
+                    .connections
+                    .borrow_mut()
+                    .shift_remove(&connection_id);
+                context.events.emit(EventKind::ConnectionClosed {
+                    connection: connection_id,
+                });
+                Ok(())
+            }
+            .instrument(tracing::info_span!(
+                "connection",
+                connection_id,
+                ?socket,
+                uid = tracing::field::Empty,
+                pid = tracing::field::Empty,
+                exe = tracing::field::Empty,
+            ))
+        })
+        .await?
+}
+
 impl AddUpstream {
     #[culpa::throws]
     pub(crate) async fn run(self) {
-        let Self { path, forward_adds } = self;
+        let Self {
+            path,
+            forward_adds,
+            identities_timeout,
+            sign_timeout,
+            sign_sk_timeout,
+            management_timeout,
+        } = self;
         let path = Rc::from(path);
-        let client = Client::new(std::env::var("SSH_AUTH_SOCK")?);
-        client.add_upstream(Upstream { path, forward_adds }).await?;
+        let timeouts = TimeoutOverrides {
+            identities: identities_timeout,
+            sign: sign_timeout,
+            sign_sk: sign_sk_timeout,
+            management: management_timeout,
+        };
+        timeouts.validate()?;
+        let client = Client::new(control_socket()?);
+        client
+            .add_upstream(Upstream {
+                path,
+                forward_adds,
+                timeouts,
+            })
+            .await?;
     }
 }
 
 impl List {
     #[culpa::throws]
     pub(crate) async fn run(self) {
-        let client = Client::new(std::env::var("SSH_AUTH_SOCK")?);
         match self {
-            Self::Identities => {
-                for key in client.request_identities().await? {
-                    dbg!(key);
+            Self::Identities { all } => {
+                let client = Client::new(std::env::var("SSH_AUTH_SOCK")?);
+                // Also refreshes the daemon's cached identities
+                let mut keys = client.request_identities().await?;
+                if all {
+                    let details = Client::new(control_socket()?).upstream_details().await?;
+                    for key in details
+                        .upstreams
+                        .into_iter()
+                        .flat_map(|upstream| upstream.identities.unwrap_or_default())
+                    {
+                        if key.certificate().is_some() && !keys.contains(&key) {
+                            keys.push(key);
+                        }
+                    }
+                    keys = packets::certificates_first(keys);
+                }
+                let now = SystemTime::now();
+                for key in keys {
+                    println!(
+                        "{} {} ({})",
+                        key.fingerprint(),
+                        key.comment(),
+                        packets::key_type(&key.blob).as_deref().unwrap_or("unknown")
+                    );
+                    if let Some(certificate) = key.certificate() {
+                        print_certificate(&certificate, now);
+                    }
                 }
             }
             Self::Upstreams => {
+                let client = Client::new(control_socket()?);
                 for upstream in client.list_upstreams().await? {
                     if upstream.forward_adds {
                         println!("{} (add identities forwarded)", upstream.path);
@@ -165,6 +864,321 @@ impl List {
     }
 }
 
+impl Audit {
+    #[culpa::throws]
+    pub(crate) fn run(self, config: &Config) {
+        let Some(path) = self.file.as_deref().or(config.audit.path.as_deref()) else {
+            bail!("no audit log configured, set audit.path in the config or pass --file");
+        };
+        let key = self.key.map(|key| {
+            if key.starts_with("SHA256:") {
+                key
+            } else {
+                format!("SHA256:{key}")
+            }
+        });
+
+        for record in audit::read(path)? {
+            if key.is_some() && record.event.fingerprint() != key.as_deref()
+                || self.upstream.is_some() && record.event.upstream() != self.upstream.as_deref()
+                || self.since.is_some_and(|since| record.time < since)
+                || self.until.is_some_and(|until| record.time > until)
+            {
+                continue;
+            }
+            if self.json {
+                println!("{}", serde_json::to_string(&record)?);
+            } else {
+                println!("{record}");
+            }
+        }
+    }
+}
+
+impl Status {
+    #[culpa::throws]
+    pub(crate) async fn run(self) {
+        let client = Client::new(control_socket()?);
+        let status = client.status().await?;
+        let ago = |ago: Duration| {
+            humantime::format_duration(Duration::from_secs(ago.as_secs())).to_string() + " ago"
+        };
+
+        println!("sshagmux {}", status.version);
+        println!(
+            "up {}",
+            humantime::format_duration(Duration::from_secs(status.uptime.as_secs()))
+        );
+        println!(
+            "bound to {}",
+            status.path.as_deref().unwrap_or("an unknown path")
+        );
+        println!("{} open connections", status.connections);
+        println!("{}", if status.locked { "locked" } else { "unlocked" });
+        println!("{} upstreams", status.upstreams.len());
+        for upstream in &status.upstreams {
+            println!(
+                "  {} ({}{})",
+                upstream.path,
+                if upstream.healthy {
+                    "healthy"
+                } else {
+                    "unhealthy"
+                },
+                if upstream.forward_adds {
+                    ", add identities forwarded"
+                } else {
+                    ""
+                },
+            );
+            match upstream.last_success {
+                Some(at) => println!("    last success {}", ago(at)),
+                None => println!("    no successful requests yet"),
+            }
+            if let Some((at, error)) = &upstream.last_failure {
+                println!("    last failure {}: {error}", ago(*at));
+            }
+            match upstream.cached {
+                Some((count, at)) => println!("    {count} identities cached {}", ago(at)),
+                None => println!("    no identities cached"),
+            }
+        }
+
+        if self.stats {
+            println!();
+            print_stats(client.stats().await?);
+        }
+
+        let mut problems = Vec::new();
+        if status.locked {
+            problems.push("locked".to_owned());
+        }
+        if status.upstreams.is_empty() {
+            problems.push("no upstreams".to_owned());
+        }
+        for upstream in &status.upstreams {
+            if !upstream.healthy {
+                problems.push(format!("upstream {} is unhealthy", upstream.path));
+            }
+        }
+        if !problems.is_empty() {
+            for problem in problems {
+                eprintln!("problem: {problem}");
+            }
+            std::process::exit(2);
+        }
+    }
+}
+
+impl Top {
+    #[culpa::throws]
+    pub(crate) async fn run(self, context: &Context) {
+        top::run(Client::new(control_socket()?), context).await?;
+    }
+}
+
+impl Decode {
+    #[culpa::throws]
+    pub(crate) fn run(self) {
+        if self.capture {
+            let path = self
+                .file
+                .as_deref()
+                .ok_or_else(|| eyre!("--capture requires a file"))?;
+            for record in capture::read(path)? {
+                print!(
+                    "{} #{} {}",
+                    humantime::format_rfc3339_millis(record.time),
+                    record.stream,
+                    record.direction
+                );
+                match (record.connection, &record.upstream) {
+                    (Some(connection), _) => println!(" connection {connection}"),
+                    (None, Some(upstream)) => println!(" {upstream}"),
+                    (None, None) => println!(),
+                }
+                match record.frame()? {
+                    Some(frame) => println!("{}", Decoded(&frame)),
+                    None => println!("type {}, redacted", record.kind),
+                }
+            }
+            return;
+        }
+
+        let input = match &self.file {
+            Some(file) => std::fs::read(file)
+                .with_context(|| format!("failed to read {:?}", file.display()))?,
+            None => {
+                let mut input = Vec::new();
+                std::io::stdin().read_to_end(&mut input)?;
+                input
+            }
+        };
+        let input = if self.hex {
+            decode::hex(std::str::from_utf8(&input).context("hex input is not text")?)?
+        } else {
+            input
+        };
+        decode::each_frame(&input, |frame| println!("{}", Decoded(&frame)))?;
+    }
+}
+
+impl Replay {
+    #[culpa::throws]
+    pub(crate) async fn run(self) {
+        let (requests, responses) = match self.upstream {
+            Some(_) => (Direction::ToUpstream, Direction::FromUpstream),
+            None => (Direction::FromClient, Direction::ToClient),
+        };
+        let to = match (&self.to, &self.upstream) {
+            (Some(to), _) => to.clone(),
+            (None, Some(upstream)) => PathBuf::from(upstream),
+            (None, None) => PathBuf::from(std::env::var("SSH_AUTH_SOCK")?),
+        };
+
+        let mut streams = IndexMap::<u64, Vec<capture::Record>>::new();
+        for record in capture::read(&self.file)? {
+            if (record.direction == requests || record.direction == responses)
+                && (self.upstream.is_none() || record.upstream == self.upstream)
+                && (self.connection.is_none() || record.connection == self.connection)
+            {
+                streams.entry(record.stream).or_default().push(record);
+            }
+        }
+        if streams.is_empty() {
+            bail!("no matching messages in {:?}", self.file.display());
+        }
+
+        let (mut same, mut different, mut skipped) = (0, 0, 0);
+        for (stream, records) in streams {
+            let label = match (&records[0].connection, &records[0].upstream) {
+                (Some(connection), _) => format!("connection {connection}"),
+                (None, Some(upstream)) => format!("{upstream} #{stream}"),
+                (None, None) => format!("#{stream}"),
+            };
+            let mut agent = pin!(Framed::new(
+                UnixStream::connect(&to)
+                    .await
+                    .with_context(|| format!("failed to connect to {:?}", to.display()))?,
+                Codec::<Frame, Frame>::new(),
+            ));
+            let mut records = records.into_iter().peekable();
+            while let Some(record) = records.next() {
+                // Responses without a request are extra messages, such as monitor events
+                if record.direction != requests {
+                    continue;
+                }
+                let expected = records.next_if(|next| next.direction == responses);
+                let Some(request) = record.frame()? else {
+                    println!("[{label}] type {}: skipped, redacted", record.kind);
+                    skipped += 1;
+                    continue;
+                };
+                let name = capture::describe_request(&request);
+                agent.send(request).await?;
+                let actual = tokio::time::timeout(self.timeout, agent.next())
+                    .await
+                    .with_context(|| format!("timed out waiting for {name} response"))?
+                    .ok_or_else(|| eyre!("agent closed the connection"))??;
+                match expected.map(|record| record.frame()).transpose()?.flatten() {
+                    Some(expected) if capture::same_response(&expected, &actual) => {
+                        println!("[{label}] {name}: same");
+                        same += 1;
+                    }
+                    Some(expected) => {
+                        println!(
+                            "[{label}] {name}: different, expected {} but got {}",
+                            capture::describe_response(&expected),
+                            capture::describe_response(&actual),
+                        );
+                        different += 1;
+                    }
+                    None => {
+                        println!(
+                            "[{label}] {name}: no captured response, got {}",
+                            capture::describe_response(&actual),
+                        );
+                        skipped += 1;
+                    }
+                }
+            }
+        }
+
+        println!("{same} same, {different} different, {skipped} skipped");
+        if different > 0 {
+            std::process::exit(2);
+        }
+    }
+}
+
+impl Monitor {
+    #[culpa::throws]
+    pub(crate) async fn run(self, context: &Context) {
+        let client = Client::new(control_socket()?);
+        let mut events = pin!(client.monitor().await?.take_until(context.shutdown.clone()));
+        while let Some(event) = events.next().await {
+            println!("{}", event?);
+        }
+    }
+}
+
+/// Prints the statistics from `stats@nemo157.com` for a human to read
+fn print_stats(families: Vec<Family>) {
+    for family in families {
+        println!("{}:", family.help);
+        if family.kind == Kind::Histogram {
+            let value = |suffix: &str| {
+                family
+                    .samples
+                    .iter()
+                    .find(|sample| sample.suffix == suffix)
+                    .map_or(0.0, |sample| sample.value)
+            };
+            let (sum, count) = (value("_sum"), value("_count"));
+            if count > 0.0 {
+                let mean = Duration::from_micros((sum / count * 1e6) as u64);
+                println!(
+                    "  {count} requests, mean {}",
+                    humantime::format_duration(mean)
+                );
+            } else {
+                println!("  none");
+            }
+            continue;
+        }
+        if family.samples.is_empty() {
+            println!("  none");
+        }
+        for sample in family.samples {
+            if sample.labels.is_empty() {
+                println!("  {}", sample.value);
+            } else {
+                let labels = sample
+                    .labels
+                    .iter()
+                    .map(|(name, value)| format!("{name}={value}"))
+                    .collect::<Vec<_>>();
+                println!("  {}: {}", labels.join(" "), sample.value);
+            }
+        }
+    }
+}
+
+/// Parses either an RFC 3339 timestamp, or a duration before now
+#[culpa::throws]
+fn parse_time(s: &str) -> SystemTime {
+    match humantime::parse_rfc3339_weak(s) {
+        Ok(time) => time,
+        Err(_) => {
+            let ago = humantime::parse_duration(s)
+                .with_context(|| format!("{s:?} is neither a timestamp nor a duration"))?;
+            SystemTime::now()
+                .checked_sub(ago)
+                .ok_or_else(|| eyre!("{s:?} is too long ago"))?
+        }
+    }
+}
+
 impl std::fmt::Display for App {
     #[culpa::throws(std::fmt::Error)]
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) {
@@ -173,6 +1187,12 @@ impl std::fmt::Display for App {
             Self::Daemon(daemon) => write!(f, " {daemon}")?,
             Self::AddUpstream(add_upstream) => write!(f, " {add_upstream}")?,
             Self::List { list } => write!(f, " {list}")?,
+            Self::Audit(audit) => write!(f, " {audit}")?,
+            Self::Status(status) => write!(f, " {status}")?,
+            Self::Monitor(monitor) => write!(f, " {monitor}")?,
+            Self::Top(top) => write!(f, " {top}")?,
+            Self::Replay(replay) => write!(f, " {replay}")?,
+            Self::Decode(decode) => write!(f, " {decode}")?,
         }
     }
 }
@@ -183,12 +1203,24 @@ impl std::fmt::Display for Daemon {
         write!(f, "daemon")?;
         if self.systemd {
             write!(f, " --systemd")?;
-        } else {
-            write!(
-                f,
-                " --bind-address={:?}",
-                self.bind_address.as_ref().unwrap().display()
-            )?;
+        }
+        if let Some(bind_address) = &self.bind_address {
+            write!(f, " --bind-address={:?}", bind_address.display())?;
+        }
+        if let Some(control_address) = &self.control_address {
+            write!(f, " --control-address={:?}", control_address.display())?;
+        }
+        if self.discover {
+            write!(f, " --discover")?;
+        }
+        if let Some(format) = self.log_format {
+            write!(f, " --log-format={format}")?;
+        }
+        if self.redact_logs {
+            write!(f, " --redact-logs")?;
+        }
+        if let Some(config) = &self.config {
+            write!(f, " --config={:?}", config.display())?;
         }
     }
 }
@@ -206,8 +1238,99 @@ impl std::fmt::Display for List {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) {
         write!(f, "list")?;
         match self {
-            Self::Identities => write!(f, " identities")?,
+            Self::Identities { all } => {
+                write!(f, " identities")?;
+                if *all {
+                    write!(f, " --all")?;
+                }
+            }
             Self::Upstreams => write!(f, " upstreams")?,
         }
     }
 }
+
+impl std::fmt::Display for Audit {
+    #[culpa::throws(std::fmt::Error)]
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) {
+        write!(f, "audit")?;
+        if let Some(key) = &self.key {
+            write!(f, " --key={key:?}")?;
+        }
+        if let Some(upstream) = &self.upstream {
+            write!(f, " --upstream={upstream:?}")?;
+        }
+        if let Some(since) = self.since {
+            write!(f, " --since={}", humantime::format_rfc3339_seconds(since))?;
+        }
+        if let Some(until) = self.until {
+            write!(f, " --until={}", humantime::format_rfc3339_seconds(until))?;
+        }
+        if self.json {
+            write!(f, " --json")?;
+        }
+        if let Some(file) = &self.file {
+            write!(f, " --file={:?}", file.display())?;
+        }
+        if let Some(config) = &self.config {
+            write!(f, " --config={:?}", config.display())?;
+        }
+    }
+}
+
+impl std::fmt::Display for Status {
+    #[culpa::throws(std::fmt::Error)]
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) {
+        write!(f, "status")?;
+        if self.stats {
+            write!(f, " --stats")?;
+        }
+    }
+}
+
+impl std::fmt::Display for Decode {
+    #[culpa::throws(std::fmt::Error)]
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) {
+        write!(f, "decode")?;
+        if self.hex {
+            write!(f, " --hex")?;
+        }
+        if self.capture {
+            write!(f, " --capture")?;
+        }
+        if let Some(file) = &self.file {
+            write!(f, " {:?}", file.display())?;
+        }
+    }
+}
+
+impl std::fmt::Display for Replay {
+    #[culpa::throws(std::fmt::Error)]
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) {
+        write!(f, "replay")?;
+        if let Some(upstream) = &self.upstream {
+            write!(f, " --upstream={upstream:?}")?;
+        }
+        if let Some(connection) = self.connection {
+            write!(f, " --connection={connection}")?;
+        }
+        if let Some(to) = &self.to {
+            write!(f, " --to={:?}", to.display())?;
+        }
+        write!(f, " --timeout={}", humantime::format_duration(self.timeout))?;
+        write!(f, " {:?}", self.file.display())?;
+    }
+}
+
+impl std::fmt::Display for Monitor {
+    #[culpa::throws(std::fmt::Error)]
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) {
+        write!(f, "monitor")?;
+    }
+}
+
+impl std::fmt::Display for Top {
+    #[culpa::throws(std::fmt::Error)]
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) {
+        write!(f, "top")?;
+    }
+}
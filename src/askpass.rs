@@ -0,0 +1,25 @@
+use eyre::{Error, WrapErr as _};
+use std::{ffi::OsString, process::Stdio};
+use tokio::process::Command;
+
+/// Asks the user to confirm `prompt` by running `$SSH_ASKPASS` (or `ssh-askpass`), the same way
+/// `ssh-agent` does for identities added with `ssh-add -c`
+#[culpa::throws]
+pub(crate) async fn confirm(prompt: &str) -> bool {
+    let program = std::env::var_os("SSH_ASKPASS")
+        .filter(|program| !program.is_empty())
+        .unwrap_or_else(|| OsString::from("ssh-askpass"));
+
+    let status = Command::new(&program)
+        .arg(prompt)
+        .env("SSH_ASKPASS_PROMPT", "confirm")
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        // The request may time out before the user answers
+        .kill_on_drop(true)
+        .status()
+        .await
+        .with_context(|| format!("failed to run {program:?}"))?;
+
+    status.success()
+}
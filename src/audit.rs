@@ -0,0 +1,269 @@
+use eyre::{Error, WrapErr as _};
+use std::{
+    cell::RefCell,
+    fs::{File, OpenOptions},
+    io::{BufRead as _, BufReader, Write as _},
+    os::unix::fs::OpenOptionsExt as _,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+use crate::net::Peer;
+
+/// One line of the audit log
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub(crate) struct Record {
+    #[serde(with = "humantime_serde")]
+    pub(crate) time: SystemTime,
+    #[serde(flatten)]
+    pub(crate) event: Event,
+}
+
+/// The process that sent a request
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub(crate) struct Requester {
+    pub(crate) pid: Option<i32>,
+    pub(crate) uid: u32,
+    pub(crate) exe: Option<PathBuf>,
+}
+
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+#[serde(tag = "event", rename_all = "kebab-case")]
+pub(crate) enum Event {
+    Sign {
+        #[serde(flatten)]
+        requester: Requester,
+        fingerprint: String,
+        flags: u32,
+        /// What was being signed, e.g. `login as deploy` or `git signature`, unset if the data
+        /// wasn't in a recognised format
+        signing: Option<String>,
+        /// The upstream that gave the signature, unset if the request failed
+        upstream: Option<String>,
+        /// Why the request failed, unset if it succeeded
+        error: Option<String>,
+        latency_ms: f64,
+        /// The host key fingerprint from the connection's last session bind
+        destination: Option<String>,
+    },
+    Add {
+        #[serde(flatten)]
+        requester: Requester,
+        key_type: String,
+        /// Unset if we couldn't decode the identity
+        fingerprint: Option<String>,
+        constrained: bool,
+        success: bool,
+    },
+    Remove {
+        #[serde(flatten)]
+        requester: Requester,
+        fingerprint: String,
+        success: bool,
+    },
+    RemoveAll {
+        #[serde(flatten)]
+        requester: Requester,
+        success: bool,
+    },
+    UpstreamAdded {
+        path: String,
+        forward_adds: bool,
+    },
+    UpstreamRemoved {
+        path: String,
+        reason: String,
+    },
+}
+
+/// Appends a JSON line to the configured file for every event, does nothing if no file is
+/// configured
+#[derive(Debug, Default)]
+pub(crate) struct AuditLog {
+    file: RefCell<Option<(PathBuf, File)>>,
+}
+
+impl From<&Peer> for Requester {
+    fn from(peer: &Peer) -> Self {
+        Self {
+            pid: peer.pid,
+            uid: peer.uid,
+            exe: peer.exe.clone(),
+        }
+    }
+}
+
+impl Event {
+    /// The key this event is about, if any
+    pub(crate) fn fingerprint(&self) -> Option<&str> {
+        match self {
+            Self::Sign { fingerprint, .. } | Self::Remove { fingerprint, .. } => Some(fingerprint),
+            Self::Add { fingerprint, .. } => fingerprint.as_deref(),
+            Self::RemoveAll { .. } | Self::UpstreamAdded { .. } | Self::UpstreamRemoved { .. } => {
+                None
+            }
+        }
+    }
+
+    /// The upstream this event is about, if any
+    pub(crate) fn upstream(&self) -> Option<&str> {
+        match self {
+            Self::Sign { upstream, .. } => upstream.as_deref(),
+            Self::UpstreamAdded { path, .. } | Self::UpstreamRemoved { path, .. } => Some(path),
+            Self::Add { .. } | Self::Remove { .. } | Self::RemoveAll { .. } => None,
+        }
+    }
+}
+
+impl AuditLog {
+    /// Starts appending to `path`, or stops logging if it's unset, the file is kept open if the
+    /// path hasn't changed
+    #[culpa::throws]
+    pub(crate) fn configure(&self, path: Option<&Path>) {
+        let mut file = self.file.borrow_mut();
+        if file.as_ref().map(|(current, _)| current.as_path()) == path {
+            return;
+        }
+        *file = None;
+        if let Some(path) = path {
+            if let Some(parent) = path.parent() {
+                std::fs::create_dir_all(parent)
+                    .with_context(|| format!("failed to create {parent:?}"))?;
+            }
+            let opened = OpenOptions::new()
+                .append(true)
+                .create(true)
+                .mode(0o600)
+                .open(path)
+                .with_context(|| format!("failed to open audit log {path:?}"))?;
+            tracing::info!(?path, "writing audit log");
+            *file = Some((path.to_owned(), opened));
+        }
+    }
+
+    pub(crate) fn record(&self, event: Event) {
+        let mut file = self.file.borrow_mut();
+        let Some((path, file)) = file.as_mut() else {
+            return;
+        };
+        let record = Record {
+            time: SystemTime::now(),
+            event,
+        };
+        let result = serde_json::to_vec(&record)
+            .map_err(Error::from)
+            .and_then(|mut line| {
+                line.push(b'\n');
+                // A single write per record so that concurrent appends can't interleave
+                file.write_all(&line)?;
+                Ok(())
+            });
+        if let Err(e) = result {
+            tracing::warn!(?path, ?record, "failed to write audit log: {e:?}");
+        }
+    }
+}
+
+/// Reads all the records from an audit log, skipping any lines that can't be parsed
+#[culpa::throws]
+pub(crate) fn read(path: &Path) -> Vec<Record> {
+    let file = File::open(path).with_context(|| format!("failed to open audit log {path:?}"))?;
+    let mut records = Vec::new();
+    for (i, line) in BufReader::new(file).lines().enumerate() {
+        let line = line?;
+        match serde_json::from_str(&line) {
+            Ok(record) => records.push(record),
+            Err(e) => tracing::warn!(line = i + 1, "skipping invalid audit record: {e}"),
+        }
+    }
+    records
+}
+
+impl std::fmt::Display for Requester {
+    #[culpa::throws(std::fmt::Error)]
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) {
+        match &self.exe {
+            Some(exe) => write!(f, "{}", exe.display())?,
+            None => write!(f, "unknown process")?,
+        }
+        match self.pid {
+            Some(pid) => write!(f, " (pid {pid}, uid {})", self.uid)?,
+            None => write!(f, " (uid {})", self.uid)?,
+        }
+    }
+}
+
+impl std::fmt::Display for Record {
+    #[culpa::throws(std::fmt::Error)]
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) {
+        let succeeded = |success| if success { "succeeded" } else { "failed" };
+        write!(f, "{} ", humantime::format_rfc3339_seconds(self.time))?;
+        match &self.event {
+            Event::Sign {
+                requester,
+                fingerprint,
+                flags,
+                signing,
+                upstream,
+                error,
+                latency_ms,
+                destination,
+            } => {
+                write!(f, "sign {fingerprint}")?;
+                if let Some(signing) = signing {
+                    write!(f, " for {signing}")?;
+                }
+                write!(f, " by {requester}")?;
+                if let Some(destination) = destination {
+                    write!(f, " for host {destination}")?;
+                }
+                if *flags != 0 {
+                    write!(f, " flags {flags:#x}")?;
+                }
+                let latency = humantime::format_duration(Duration::from_millis(*latency_ms as u64));
+                match (upstream, error) {
+                    (Some(upstream), _) => write!(f, ": signed by {upstream} in {latency}")?,
+                    (None, Some(error)) => write!(f, ": failed after {latency}, {error}")?,
+                    (None, None) => write!(f, ": failed after {latency}")?,
+                }
+            }
+            Event::Add {
+                requester,
+                key_type,
+                fingerprint,
+                constrained,
+                success,
+            } => {
+                write!(f, "add {key_type}")?;
+                if let Some(fingerprint) = fingerprint {
+                    write!(f, " {fingerprint}")?;
+                }
+                if *constrained {
+                    write!(f, " (constrained)")?;
+                }
+                write!(f, " by {requester}: {}", succeeded(*success))?;
+            }
+            Event::Remove {
+                requester,
+                fingerprint,
+                success,
+            } => write!(
+                f,
+                "remove {fingerprint} by {requester}: {}",
+                succeeded(*success)
+            )?,
+            Event::RemoveAll { requester, success } => {
+                write!(f, "remove all by {requester}: {}", succeeded(*success))?
+            }
+            Event::UpstreamAdded { path, forward_adds } => {
+                write!(f, "upstream added {path}")?;
+                if *forward_adds {
+                    write!(f, " (add identities forwarded)")?;
+                }
+            }
+            Event::UpstreamRemoved { path, reason } => {
+                write!(f, "upstream removed {path}: {reason}")?
+            }
+        }
+    }
+}
@@ -0,0 +1,239 @@
+use base64::Engine as _;
+use bytes::Bytes;
+use eyre::{Error, WrapErr as _};
+use std::{
+    cell::{Cell, RefCell},
+    fs::{File, OpenOptions},
+    io::{BufRead as _, BufReader, Write as _},
+    os::unix::fs::OpenOptionsExt as _,
+    path::{Path, PathBuf},
+    rc::Rc,
+    time::SystemTime,
+};
+
+use crate::packets::{Frame, Parse as _, Request, Response};
+
+/// Which way a captured message was going, relative to the daemon
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum Direction {
+    FromClient,
+    ToClient,
+    ToUpstream,
+    FromUpstream,
+}
+
+/// One line of a capture file
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub(crate) struct Record {
+    #[serde(with = "humantime_serde")]
+    pub(crate) time: SystemTime,
+    /// Identifies a single socket connection within the capture, the messages on it are in order
+    pub(crate) stream: u64,
+    pub(crate) direction: Direction,
+    /// The downstream connection, the same id as in the logs and `sshagmux monitor`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) connection: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) upstream: Option<String>,
+    /// The message type
+    pub(crate) kind: u8,
+    /// The base64 encoded message after the type, unset if it was redacted
+    pub(crate) contents: Option<String>,
+}
+
+/// Appends a JSON line to the configured file for every message sent or received by the daemon,
+/// does nothing if no file is configured
+#[derive(Debug, Default)]
+pub(crate) struct Capture {
+    file: RefCell<Option<(PathBuf, File)>>,
+    next_stream: Cell<u64>,
+}
+
+/// Records the messages passing through a `Codec` to a capture
+#[derive(Debug, Clone)]
+pub(crate) struct Tap {
+    capture: Rc<Capture>,
+    stream: u64,
+    connection: Option<u64>,
+    upstream: Option<Rc<str>>,
+}
+
+impl Record {
+    /// The captured message, or `None` if it was redacted
+    #[culpa::throws]
+    pub(crate) fn frame(&self) -> Option<Frame> {
+        let Some(contents) = &self.contents else {
+            return None;
+        };
+        let contents = base64::engine::general_purpose::STANDARD
+            .decode(contents)
+            .context("invalid message contents")?;
+        Some(Frame {
+            kind: self.kind,
+            contents: Bytes::from(contents),
+        })
+    }
+}
+
+impl Capture {
+    /// Starts appending to `path`, or stops capturing if it's unset, the file is kept open if the
+    /// path hasn't changed
+    #[culpa::throws]
+    pub(crate) fn configure(&self, path: Option<&Path>) {
+        let mut file = self.file.borrow_mut();
+        if file.as_ref().map(|(current, _)| current.as_path()) == path {
+            return;
+        }
+        *file = None;
+        if let Some(path) = path {
+            if let Some(parent) = path.parent() {
+                std::fs::create_dir_all(parent)
+                    .with_context(|| format!("failed to create {parent:?}"))?;
+            }
+            let opened = OpenOptions::new()
+                .append(true)
+                .create(true)
+                .mode(0o600)
+                .open(path)
+                .with_context(|| format!("failed to open capture {path:?}"))?;
+            tracing::info!(?path, "capturing protocol messages");
+            *file = Some((path.to_owned(), opened));
+        }
+    }
+
+    /// A tap for the messages to and from a downstream connection
+    pub(crate) fn client(self: &Rc<Self>, connection: u64) -> Tap {
+        Tap {
+            capture: self.clone(),
+            stream: self.next_stream.replace(self.next_stream.get() + 1),
+            connection: Some(connection),
+            upstream: None,
+        }
+    }
+
+    /// A tap for the messages to and from a single connection to an upstream
+    pub(crate) fn upstream(self: &Rc<Self>, path: Rc<str>) -> Tap {
+        Tap {
+            capture: self.clone(),
+            stream: self.next_stream.replace(self.next_stream.get() + 1),
+            connection: None,
+            upstream: Some(path),
+        }
+    }
+
+    fn record(&self, tap: &Tap, direction: Direction, kind: u8, contents: &[u8]) {
+        let mut file = self.file.borrow_mut();
+        let Some((path, file)) = file.as_mut() else {
+            return;
+        };
+        // Private keys, lock passphrases and smartcard PINs never leave the daemon's memory
+        let redacted = matches!(direction, Direction::FromClient | Direction::ToUpstream)
+            && Request::carries_secrets(kind);
+        let record = Record {
+            time: SystemTime::now(),
+            stream: tap.stream,
+            direction,
+            connection: tap.connection,
+            upstream: tap.upstream.as_deref().map(str::to_owned),
+            kind,
+            contents: (!redacted)
+                .then(|| base64::engine::general_purpose::STANDARD.encode(contents)),
+        };
+        let result = serde_json::to_vec(&record)
+            .map_err(Error::from)
+            .and_then(|mut line| {
+                line.push(b'\n');
+                // A single write per record so that concurrent appends can't interleave
+                file.write_all(&line)?;
+                Ok(())
+            });
+        if let Err(e) = result {
+            tracing::warn!(?path, "failed to write capture: {e:?}");
+        }
+    }
+}
+
+impl Tap {
+    /// Records a message the daemon received
+    pub(crate) fn received(&self, kind: u8, contents: &[u8]) {
+        let direction = match self.connection {
+            Some(_) => Direction::FromClient,
+            None => Direction::FromUpstream,
+        };
+        self.capture.record(self, direction, kind, contents);
+    }
+
+    /// Records a message the daemon sent
+    pub(crate) fn sent(&self, kind: u8, contents: &[u8]) {
+        let direction = match self.connection {
+            Some(_) => Direction::ToClient,
+            None => Direction::ToUpstream,
+        };
+        self.capture.record(self, direction, kind, contents);
+    }
+}
+
+/// The request's type, or the extension name for extensions
+pub(crate) fn describe_request(frame: &Frame) -> String {
+    match Request::parse(frame.kind, frame.contents.clone()) {
+        Ok(Request::Extension(extension)) => extension.kind().to_owned(),
+        Ok(request) => request.name().to_owned(),
+        Err(e) => format!("invalid request type {}: {e}", frame.kind),
+    }
+}
+
+/// The response's type, along with the keys for identities answers
+pub(crate) fn describe_response(frame: &Frame) -> String {
+    match Response::parse(frame.kind, frame.contents.clone()) {
+        Ok(Response::Identities { keys }) => format!(
+            "identities_answer [{}]",
+            keys.iter()
+                .map(|key| key.fingerprint())
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+        Ok(response) if frame.contents.is_empty() => response.name().to_owned(),
+        Ok(response) => format!("{} ({} bytes)", response.name(), frame.contents.len()),
+        Err(e) => format!("invalid response type {}: {e}", frame.kind),
+    }
+}
+
+/// Whether a replayed response matches the captured one, signatures aren't compared as they differ
+/// every time for some key types
+pub(crate) fn same_response(expected: &Frame, actual: &Frame) -> bool {
+    match (
+        Response::parse(expected.kind, expected.contents.clone()),
+        Response::parse(actual.kind, actual.contents.clone()),
+    ) {
+        (Ok(Response::SignResponse { .. }), Ok(Response::SignResponse { .. })) => true,
+        _ => expected == actual,
+    }
+}
+
+/// Reads all the records from a capture, skipping any lines that can't be parsed
+#[culpa::throws]
+pub(crate) fn read(path: &Path) -> Vec<Record> {
+    let file = File::open(path).with_context(|| format!("failed to open capture {path:?}"))?;
+    let mut records = Vec::new();
+    for (i, line) in BufReader::new(file).lines().enumerate() {
+        let line = line?;
+        match serde_json::from_str(&line) {
+            Ok(record) => records.push(record),
+            Err(e) => tracing::warn!(line = i + 1, "skipping invalid capture record: {e}"),
+        }
+    }
+    records
+}
+
+impl std::fmt::Display for Direction {
+    #[culpa::throws(std::fmt::Error)]
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) {
+        match self {
+            Self::FromClient => write!(f, "from client")?,
+            Self::ToClient => write!(f, "to client")?,
+            Self::ToUpstream => write!(f, "to upstream")?,
+            Self::FromUpstream => write!(f, "from upstream")?,
+        }
+    }
+}
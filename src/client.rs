@@ -4,19 +4,41 @@ use futures::{
     sink::{Sink, SinkExt},
     stream::{Stream, StreamExt, TryStreamExt},
 };
-use std::{pin::pin, rc::Rc, time::Duration};
+use secrecy::SecretBytesMut;
+use std::{path::Path, pin::pin, rc::Rc, time::Duration};
 use tokio::net::UnixStream;
 use tokio_util::codec::Framed;
 
 use crate::{
-    packets::{Codec, Extension, NoResponse, PublicKey, Request, Response, UpstreamListV2},
+    capture::Capture,
+    config::{TimeoutOverrides, Timeouts},
+    keystore::{KeyStore, KEYSTORE_PATH},
+    metrics::Family,
+    monitor::Event,
+    net::{self, SocketId, SocketReplaced},
+    packets::{
+        self, Codec, DaemonStatus, Extension, Logged, NoResponse, PublicKey, Request, Response,
+        Stats, UpstreamDetails, UpstreamListV2,
+    },
     upstreams::Upstream,
 };
 
-#[derive(Debug, Eq, PartialEq, Hash)]
+#[derive(Debug, Clone)]
 pub(crate) struct Client {
     pub(crate) path: Rc<str>,
     pub(crate) forward_adds: bool,
+    /// Static upstreams from the config are kept even if their socket disappears
+    pub(crate) persistent: bool,
+    /// The overrides applied on top of the configured timeouts to get `timeouts`
+    pub(crate) timeout_overrides: TimeoutOverrides,
+    pub(crate) timeouts: Timeouts,
+    /// Requests are answered by the built-in keystore rather than sent to a socket
+    pub(crate) keystore: Option<Rc<KeyStore>>,
+    /// The socket that was verified when this upstream was added, connecting fails if the path
+    /// refers to a different socket
+    pub(crate) socket: Option<SocketId>,
+    /// Where to record the messages sent to and received from this upstream
+    pub(crate) capture: Option<Rc<Capture>>,
 }
 
 impl From<Upstream> for Client {
@@ -24,6 +46,12 @@ impl From<Upstream> for Client {
         Self {
             path: upstream.path,
             forward_adds: upstream.forward_adds,
+            persistent: false,
+            timeout_overrides: upstream.timeouts,
+            timeouts: upstream.timeouts.apply(Timeouts::default()),
+            keystore: None,
+            socket: None,
+            capture: None,
         }
     }
 }
@@ -34,6 +62,25 @@ impl Client {
         Client {
             path: Rc::from(path.as_ref()),
             forward_adds: false,
+            persistent: false,
+            timeout_overrides: TimeoutOverrides::default(),
+            timeouts: Timeouts::default(),
+            keystore: None,
+            socket: None,
+            capture: None,
+        }
+    }
+
+    pub(crate) fn keystore(keystore: Rc<KeyStore>, timeouts: Timeouts) -> Self {
+        Client {
+            path: Rc::from(KEYSTORE_PATH),
+            forward_adds: true,
+            persistent: true,
+            timeout_overrides: TimeoutOverrides::default(),
+            timeouts,
+            keystore: Some(keystore),
+            socket: None,
+            capture: None,
         }
     }
 
@@ -41,6 +88,7 @@ impl Client {
         Upstream {
             path: self.path.clone(),
             forward_adds: self.forward_adds,
+            timeouts: self.timeout_overrides,
         }
     }
 
@@ -48,19 +96,31 @@ impl Client {
     async fn connect(
         &self,
     ) -> impl Stream<Item = Result<Response, Error>> + Sink<Request, Error = Error> {
+        if let Some(expected) = self.socket {
+            if net::verify_socket(Path::new(self.path.as_ref()))? != expected {
+                bail!(SocketReplaced);
+            }
+        }
         Framed::new(
             UnixStream::connect(self.path.as_ref()).await?,
-            Codec::<Response, Request>::new(),
+            Codec::<Response, Request>::new().tap(
+                self.capture
+                    .as_ref()
+                    .map(|capture| capture.upstream(self.path.clone())),
+            ),
         )
-        .inspect_ok(|response| tracing::debug!(?response, "received"))
+        .inspect_ok(|response| tracing::debug!(response = ?Logged(response), "received"))
         .with(|request| {
-            tracing::debug!(?request, "sending");
+            tracing::debug!(request = ?Logged(&request), "sending");
             async move { Ok::<_, Error>(request) }
         })
     }
 
     #[culpa::throws]
     pub(crate) async fn send(&self, request: Request, timeout: Duration) -> Response {
+        if let Some(keystore) = &self.keystore {
+            return tokio::time::timeout(timeout, keystore.handle(request)).await?;
+        }
         let mut stream = pin!(self.connect().await?);
         stream.send(request).await?;
         tokio::time::timeout(timeout, stream.next())
@@ -69,12 +129,10 @@ impl Client {
     }
 
     #[culpa::throws]
-    #[tracing::instrument(fields(?self.path), skip(self))]
+    #[tracing::instrument(fields(path = %self.path), skip(self))]
     pub(crate) async fn request_identities(&self) -> Vec<PublicKey> {
-        // The windows agent at least can be quite slow even when it only has a single identity to
-        // return....
         match self
-            .send(Request::RequestIdentities, Duration::from_secs(5))
+            .send(Request::RequestIdentities, self.timeouts.identities)
             .await?
         {
             Response::Identities { keys } => keys,
@@ -88,14 +146,15 @@ impl Client {
     }
 
     #[culpa::throws]
-    #[tracing::instrument(fields(?self.path), skip(self, blob, data, flags))]
+    #[tracing::instrument(fields(path = %self.path), skip(self, blob, data, flags))]
     pub(crate) async fn sign_request(&self, blob: Bytes, data: Bytes, flags: u32) -> Option<Bytes> {
-        // Needs a long timeout as it may require human interaction
+        let timeout = if packets::is_security_key(&blob) {
+            self.timeouts.sign_sk
+        } else {
+            self.timeouts.sign
+        };
         match self
-            .send(
-                Request::SignRequest { blob, data, flags },
-                Duration::from_secs(60),
-            )
+            .send(Request::SignRequest { blob, data, flags }, timeout)
             .await?
         {
             Response::SignResponse { signature } => Some(signature),
@@ -109,25 +168,128 @@ impl Client {
     }
 
     #[culpa::throws]
-    #[tracing::instrument(fields(?self.path), skip(self))]
+    #[tracing::instrument(fields(path = %self.path), skip(self))]
     pub(crate) async fn list_upstreams(&self) -> Vec<Upstream> {
         self.send(
             Request::Extension(Extension::ListUpstreamsV2),
-            Duration::from_secs(1),
+            self.timeouts.management,
         )
         .await?
         .parse_extension::<UpstreamListV2>()?
         .upstreams
     }
 
+    /// Whether this upstream is the daemon with this instance id, agents that don't know the
+    /// extension refuse it
     #[culpa::throws]
-    #[tracing::instrument(fields(?self.path), skip(self))]
-    pub(crate) async fn add_upstream(&self, upstream: Upstream) {
+    #[tracing::instrument(fields(path = %self.path), skip(self))]
+    pub(crate) async fn is_instance(&self, instance: u64) -> bool {
+        matches!(
+            self.send(
+                Request::Extension(Extension::LoopCheck(instance)),
+                self.timeouts.management,
+            )
+            .await?,
+            Response::Success { .. }
+        )
+    }
+
+    #[culpa::throws]
+    #[tracing::instrument(fields(path = %self.path), skip(self))]
+    pub(crate) async fn stats(&self) -> Vec<Family> {
+        self.send(
+            Request::Extension(Extension::Stats),
+            self.timeouts.management,
+        )
+        .await?
+        .parse_extension::<Stats>()?
+        .families
+    }
+
+    #[culpa::throws]
+    #[tracing::instrument(fields(path = %self.path), skip(self))]
+    pub(crate) async fn status(&self) -> DaemonStatus {
+        self.send(
+            Request::Extension(Extension::Status),
+            self.timeouts.management,
+        )
+        .await?
+        .parse_extension::<DaemonStatus>()?
+    }
+
+    #[culpa::throws]
+    #[tracing::instrument(fields(path = %self.path), skip(self))]
+    pub(crate) async fn upstream_details(&self) -> UpstreamDetails {
+        self.send(
+            Request::Extension(Extension::UpstreamDetails),
+            self.timeouts.management,
+        )
+        .await?
+        .parse_extension::<UpstreamDetails>()?
+    }
+
+    #[culpa::throws]
+    #[tracing::instrument(fields(path = %self.path), skip(self))]
+    pub(crate) async fn remove_upstream(&self, path: Rc<str>) {
         self.send(
-            Request::Extension(Extension::AddUpstreamV2(upstream)),
-            Duration::from_secs(1),
+            Request::Extension(Extension::RemoveUpstream(path)),
+            self.timeouts.management,
         )
         .await?
         .parse_extension::<NoResponse>()?;
     }
+
+    #[culpa::throws]
+    #[tracing::instrument(fields(path = %self.path), skip(self))]
+    pub(crate) async fn set_preferred(&self, path: Rc<str>) {
+        self.send(
+            Request::Extension(Extension::SetPreferred(path)),
+            self.timeouts.management,
+        )
+        .await?
+        .parse_extension::<NoResponse>()?;
+    }
+
+    /// The same as `ssh-add -x`
+    #[culpa::throws]
+    #[tracing::instrument(fields(path = %self.path), skip(self, passphrase))]
+    pub(crate) async fn lock(&self, passphrase: SecretBytesMut) {
+        match self
+            .send(Request::Lock { passphrase }, self.timeouts.management)
+            .await?
+        {
+            Response::Success { .. } => {}
+            Response::Failure { .. } => {
+                bail!("agent refused to lock, it or one of its upstreams may already be locked")
+            }
+            _ => bail!("server returned unexpected response"),
+        }
+    }
+
+    /// Subscribes to the daemon's events, the stream ends when the daemon closes the connection
+    #[culpa::throws]
+    #[tracing::instrument(fields(path = %self.path), skip(self))]
+    pub(crate) async fn monitor(&self) -> impl Stream<Item = Result<Event, Error>> {
+        let mut stream = Box::pin(self.connect().await?);
+        stream.send(Request::Extension(Extension::Monitor)).await?;
+        tokio::time::timeout(self.timeouts.management, stream.next())
+            .await?
+            .ok_or(eyre!("no response from server"))??
+            .parse_extension::<NoResponse>()?;
+        stream.map(|response| response?.parse_extension::<Event>())
+    }
+
+    #[culpa::throws]
+    #[tracing::instrument(fields(path = %self.path), skip(self))]
+    pub(crate) async fn add_upstream(&self, upstream: Upstream) {
+        // Older daemons only understand v2, so only use v3 when it's needed
+        let extension = if upstream.timeouts.is_empty() {
+            Extension::AddUpstreamV2(upstream)
+        } else {
+            Extension::AddUpstreamV3(upstream)
+        };
+        self.send(Request::Extension(extension), self.timeouts.management)
+            .await?
+            .parse_extension::<NoResponse>()?;
+    }
 }
@@ -0,0 +1,742 @@
+use eyre::{bail, eyre, Error, WrapErr as _};
+use std::{
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    rc::Rc,
+    time::Duration,
+};
+use tracing_subscriber::EnvFilter;
+
+use crate::{
+    keystore::KEYSTORE_PATH,
+    known_hosts::{self, Host},
+};
+
+/// The daemon configuration, read from `$XDG_CONFIG_HOME/sshagmux/config.toml` by default
+#[derive(Debug, Default, serde::Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub(crate) struct Config {
+    pub(crate) daemon: DaemonConfig,
+    pub(crate) access: Access,
+    pub(crate) timeouts: Timeouts,
+    pub(crate) shutdown: Shutdown,
+    pub(crate) routing: Routing,
+    pub(crate) keystore: KeyStoreConfig,
+    pub(crate) confirm: Confirm,
+    pub(crate) signing: Signing,
+    pub(crate) destinations: Destinations,
+    pub(crate) certificates: Certificates,
+    pub(crate) audit: AuditConfig,
+    pub(crate) capture: CaptureConfig,
+    pub(crate) metrics: MetricsConfig,
+    pub(crate) logging: Logging,
+    pub(crate) upstreams: Vec<StaticUpstream>,
+}
+
+#[derive(Debug, Default, PartialEq, Eq, serde::Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub(crate) struct DaemonConfig {
+    /// Used if neither `--bind-address` nor `--systemd` are passed
+    pub(crate) bind_address: Option<PathBuf>,
+    /// Same as passing `--control-address`
+    pub(crate) control_address: Option<PathBuf>,
+    /// Same as passing `--discover`
+    pub(crate) discover: bool,
+}
+
+/// Which processes may connect to the daemon's socket, checked with `SO_PEERCRED`
+#[derive(Debug, Default, serde::Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub(crate) struct Access {
+    /// Users other than the one running the daemon that are allowed to connect
+    pub(crate) allowed_uids: Vec<u32>,
+}
+
+/// How long to wait for a response from an upstream
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub(crate) struct Timeouts {
+    #[serde(with = "humantime_serde")]
+    pub(crate) identities: Duration,
+    #[serde(with = "humantime_serde")]
+    pub(crate) sign: Duration,
+    /// Used instead of `sign` for `sk-*` security key types
+    #[serde(with = "humantime_serde")]
+    pub(crate) sign_sk: Duration,
+    /// Used for forwarding add/remove identity requests and our own extensions
+    #[serde(with = "humantime_serde")]
+    pub(crate) management: Duration,
+}
+
+/// Per-upstream overrides of the configured `Timeouts`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub(crate) struct TimeoutOverrides {
+    #[serde(with = "humantime_serde")]
+    pub(crate) identities: Option<Duration>,
+    #[serde(with = "humantime_serde")]
+    pub(crate) sign: Option<Duration>,
+    #[serde(with = "humantime_serde")]
+    pub(crate) sign_sk: Option<Duration>,
+    #[serde(with = "humantime_serde")]
+    pub(crate) management: Option<Duration>,
+}
+
+/// How long to wait on each stage of shutdown after receiving a SIGINT/SIGTERM
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub(crate) struct Shutdown {
+    /// How long to let running requests finish before aborting them
+    #[serde(with = "humantime_serde")]
+    pub(crate) graceful: Duration,
+    /// How long to wait after aborting before exiting regardless
+    #[serde(with = "humantime_serde")]
+    pub(crate) hard: Duration,
+}
+
+#[derive(Debug, Clone, Default, serde::Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub(crate) struct Routing {
+    /// An upstream to try before all others, instead of preferring the most recently added
+    pub(crate) preferred: Option<String>,
+    /// Where to send add identity requests that don't match any of `add_rules`
+    pub(crate) adds: Target,
+    /// Checked in order, the first matching rule decides where an add identity request is sent
+    pub(crate) add_rules: Vec<AddRule>,
+    /// Where to send remove all identities requests
+    pub(crate) remove_all: Target,
+    /// Where to send requests and extensions that we don't understand
+    pub(crate) unknown: Passthrough,
+}
+
+/// Which upstreams to forward a request to
+#[derive(Debug, Clone, Default, PartialEq, Eq, serde::Deserialize)]
+#[serde(from = "String")]
+pub(crate) enum Target {
+    /// The first upstream with `forward-adds` set, in the same order identities are requested
+    #[default]
+    Latest,
+    /// All upstreams with `forward-adds` set
+    Broadcast,
+    /// The built-in keystore, if it's enabled
+    KeyStore,
+    /// A specific upstream, whether or not it has `forward-adds` set
+    Upstream(String),
+}
+
+/// How to pass through requests that we don't understand, the response is relayed unchanged
+#[derive(Debug, Clone, Default, PartialEq, Eq, serde::Deserialize)]
+#[serde(from = "String")]
+pub(crate) enum Passthrough {
+    /// Answer with a failure without forwarding them anywhere
+    #[default]
+    Refuse,
+    /// The first upstream in the same order identities are requested
+    Preferred,
+    /// The same upstreams as `adds`
+    Adds,
+    /// Every upstream in turn until one answers with something other than a failure
+    FanOut,
+    /// A specific upstream
+    Upstream(String),
+}
+
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub(crate) struct AddRule {
+    /// A pattern matched against the key type, `*` matches any sequence of characters
+    #[serde(default)]
+    pub(crate) key_type: Option<String>,
+    /// Whether the rule only matches requests with (or without) constraints such as a lifetime
+    #[serde(default)]
+    pub(crate) constrained: Option<bool>,
+    pub(crate) to: Target,
+}
+
+/// An agent inside the daemon that holds identities added to it in memory
+#[derive(Debug, Default, serde::Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub(crate) struct KeyStoreConfig {
+    /// Acts as an upstream with `forward-adds` set that is always tried after all other upstreams
+    pub(crate) enable: bool,
+}
+
+/// Sign requests that the user must confirm with `$SSH_ASKPASS` before they're forwarded
+#[derive(Debug, Default, serde::Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub(crate) struct Confirm {
+    /// Confirm every sign request
+    pub(crate) all: bool,
+    /// Keys to confirm, as `SHA256:...` fingerprints like `ssh-add -l` shows
+    pub(crate) fingerprints: Vec<String>,
+    /// Upstreams whose keys all need confirming
+    pub(crate) upstreams: Vec<String>,
+}
+
+/// Restrictions on `SSHSIG` signatures (`ssh-keygen -Y sign`), such as git commit signatures,
+/// other sign requests are unaffected
+#[derive(Debug, Default, serde::Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub(crate) struct Signing {
+    /// Checked in order, the first rule matching the key and namespace decides, signatures that
+    /// don't match any rule are allowed
+    pub(crate) rules: Vec<SigningRule>,
+}
+
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub(crate) struct SigningRule {
+    /// The key's `SHA256:...` fingerprint, matches any key if unset
+    #[serde(default)]
+    pub(crate) fingerprint: Option<String>,
+    /// A pattern matched against the namespace, `*` matches any sequence of characters
+    #[serde(default)]
+    pub(crate) namespace: Option<String>,
+    /// Whether matching signatures are allowed
+    pub(crate) allow: bool,
+    /// Only sign through these upstreams, any upstream if empty
+    #[serde(default)]
+    pub(crate) upstreams: Vec<String>,
+}
+
+/// Restrictions on which hosts keys may be used to log in to, checked against the host key that
+/// `ssh` sends with `session-bind@openssh.com`
+#[derive(Debug, Default, serde::Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub(crate) struct Destinations {
+    /// Where to find the names of hosts from their keys, `ssh`'s default files if empty
+    pub(crate) known_hosts: Vec<PathBuf>,
+    /// Checked in order, the first rule matching the key and the upstream holding it decides,
+    /// keys that don't match any rule may be used anywhere
+    pub(crate) rules: Vec<DestinationRule>,
+}
+
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub(crate) struct DestinationRule {
+    /// The key's `SHA256:...` fingerprint, matches any key if unset
+    #[serde(default)]
+    pub(crate) fingerprint: Option<String>,
+    /// Only matches keys held by this upstream, matches any upstream if unset
+    #[serde(default)]
+    pub(crate) upstream: Option<String>,
+    /// The hosts the key may log in to, as `SHA256:...` host key fingerprints or patterns matched
+    /// against the names known hosts files give the host key, `*` matches any sequence of
+    /// characters
+    pub(crate) hosts: Vec<String>,
+}
+
+/// How OpenSSH certificate identities are handled
+#[derive(Debug, serde::Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub(crate) struct Certificates {
+    /// Leave certificates that are expired or not yet valid out of identities answers, servers
+    /// would reject them anyway
+    pub(crate) hide_invalid: bool,
+}
+
+/// A record of every signing operation and change to the identities and upstreams
+#[derive(Debug, Default, serde::Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub(crate) struct AuditConfig {
+    /// The file to append JSON lines to, nothing is recorded if unset
+    pub(crate) path: Option<PathBuf>,
+}
+
+/// A record of every message sent and received, for debugging interoperability with agents
+#[derive(Debug, Default, serde::Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub(crate) struct CaptureConfig {
+    /// The file to append JSON lines to, nothing is captured if unset
+    pub(crate) path: Option<PathBuf>,
+}
+
+/// Statistics about requests and upstreams, also available with `sshagmux status`
+#[derive(Debug, Default, PartialEq, Eq, serde::Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub(crate) struct MetricsConfig {
+    /// Where to serve the metrics in the Prometheus text format over HTTP, not served if unset
+    pub(crate) listen: Option<MetricsAddress>,
+}
+
+/// A unix socket path, or a loopback `address:port` to listen on with TCP
+#[derive(Debug, Clone, PartialEq, Eq, serde::Deserialize)]
+#[serde(from = "String")]
+pub(crate) enum MetricsAddress {
+    Unix(PathBuf),
+    Tcp(SocketAddr),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, serde::Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub(crate) struct Logging {
+    /// A `tracing_subscriber::EnvFilter` directive, `SSHAGMUX_LOG` takes precedence if set
+    pub(crate) filter: String,
+    /// Same as passing `--log-format`
+    pub(crate) format: LogFormat,
+    /// Same as passing `--redact-logs`
+    pub(crate) redact: bool,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum LogFormat {
+    /// Human readable lines on stderr
+    #[default]
+    Compact,
+    /// A JSON object per line on stderr, including the fields of the surrounding spans
+    Json,
+    /// Sent straight to the systemd journal, with each field as a separate journal field
+    Journald,
+}
+
+/// An upstream that is always present, such as a local `ssh-agent` or `gpg-agent`
+#[derive(Debug, serde::Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub(crate) struct StaticUpstream {
+    pub(crate) path: String,
+    #[serde(default)]
+    pub(crate) forward_adds: bool,
+    #[serde(default)]
+    pub(crate) timeouts: TimeoutOverrides,
+}
+
+impl From<String> for Target {
+    fn from(target: String) -> Self {
+        match target.as_str() {
+            "latest" => Self::Latest,
+            "broadcast" => Self::Broadcast,
+            "keystore" => Self::KeyStore,
+            _ => Self::Upstream(target),
+        }
+    }
+}
+
+impl From<String> for Passthrough {
+    fn from(passthrough: String) -> Self {
+        match passthrough.as_str() {
+            "refuse" => Self::Refuse,
+            "preferred" => Self::Preferred,
+            "adds" => Self::Adds,
+            "fan-out" => Self::FanOut,
+            _ => Self::Upstream(passthrough),
+        }
+    }
+}
+
+impl From<String> for MetricsAddress {
+    fn from(s: String) -> Self {
+        match s.parse() {
+            Ok(addr) => Self::Tcp(addr),
+            Err(_) => Self::Unix(PathBuf::from(s)),
+        }
+    }
+}
+
+impl Confirm {
+    /// Whether a sign request needs confirming, given the upstreams that hold the key
+    pub(crate) fn requires(&self, fingerprint: &str, holders: &[Rc<str>]) -> bool {
+        self.all
+            || self.fingerprints.iter().any(|f| f == fingerprint)
+            || holders
+                .iter()
+                .any(|holder| self.upstreams.iter().any(|path| path == holder.as_ref()))
+    }
+
+    /// Whether we need to know which upstreams hold a key to decide
+    pub(crate) fn by_upstream(&self) -> bool {
+        !self.all && !self.upstreams.is_empty()
+    }
+}
+
+impl Signing {
+    /// The first rule matching this signature, along with its index
+    pub(crate) fn rule(&self, fingerprint: &str, namespace: &str) -> Option<(usize, &SigningRule)> {
+        self.rules.iter().enumerate().find(|(_, rule)| {
+            rule.fingerprint.as_ref().is_none_or(|f| f == fingerprint)
+                && rule
+                    .namespace
+                    .as_ref()
+                    .is_none_or(|pattern| glob_matches(pattern, namespace))
+        })
+    }
+}
+
+impl Destinations {
+    /// The known hosts files to read
+    pub(crate) fn known_hosts_files(&self) -> Vec<PathBuf> {
+        if self.known_hosts.is_empty() {
+            known_hosts::default_files()
+        } else {
+            self.known_hosts.clone()
+        }
+    }
+
+    /// The first rule matching a key held by `upstream`, along with its index
+    pub(crate) fn rule(
+        &self,
+        fingerprint: &str,
+        upstream: &str,
+    ) -> Option<(usize, &DestinationRule)> {
+        self.rules.iter().enumerate().find(|(_, rule)| {
+            rule.fingerprint.as_ref().is_none_or(|f| f == fingerprint)
+                && rule.upstream.as_ref().is_none_or(|path| path == upstream)
+        })
+    }
+
+    /// Whether any rule could restrict this key, depending on which upstream holds it
+    pub(crate) fn restricts(&self, fingerprint: &str) -> bool {
+        self.rules
+            .iter()
+            .any(|rule| rule.fingerprint.as_ref().is_none_or(|f| f == fingerprint))
+    }
+
+    /// The index of the rule that stops a key held by `upstream` logging in to `host`, keys
+    /// matching a rule can't be used when the host isn't known
+    pub(crate) fn denied_by(
+        &self,
+        fingerprint: &str,
+        upstream: &str,
+        host: Option<&Host>,
+    ) -> Option<usize> {
+        let (i, rule) = self.rule(fingerprint, upstream)?;
+        match host {
+            Some(host) if rule.allows(host) => None,
+            _ => Some(i),
+        }
+    }
+}
+
+impl DestinationRule {
+    pub(crate) fn allows(&self, host: &Host) -> bool {
+        self.hosts.iter().any(|pattern| {
+            if pattern.starts_with("SHA256:") {
+                *pattern == host.fingerprint
+            } else {
+                host.names.iter().any(|name| glob_matches(pattern, name))
+            }
+        })
+    }
+}
+
+impl AddRule {
+    pub(crate) fn matches(&self, key_type: &str, constrained: bool) -> bool {
+        self.key_type
+            .as_ref()
+            .is_none_or(|pattern| glob_matches(pattern, key_type))
+            && self
+                .constrained
+                .is_none_or(|expected| expected == constrained)
+    }
+}
+
+impl Access {
+    pub(crate) fn allows(&self, uid: u32) -> bool {
+        uid == nix::unistd::geteuid().as_raw() || self.allowed_uids.contains(&uid)
+    }
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Self {
+            // The windows agent at least can be quite slow even when it only has a single identity
+            // to return....
+            identities: Duration::from_secs(5),
+            // Needs a long timeout as it may require human interaction
+            sign: Duration::from_secs(60),
+            // Security keys always require a touch, and may need to be found and plugged in first
+            sign_sk: Duration::from_secs(120),
+            management: Duration::from_secs(1),
+        }
+    }
+}
+
+impl TimeoutOverrides {
+    pub(crate) fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    pub(crate) fn apply(&self, timeouts: Timeouts) -> Timeouts {
+        Timeouts {
+            identities: self.identities.unwrap_or(timeouts.identities),
+            sign: self.sign.unwrap_or(timeouts.sign),
+            sign_sk: self.sign_sk.unwrap_or(timeouts.sign_sk),
+            management: self.management.unwrap_or(timeouts.management),
+        }
+    }
+
+    #[culpa::throws]
+    pub(crate) fn validate(&self) {
+        for (name, timeout) in [
+            ("identities", self.identities),
+            ("sign", self.sign),
+            ("sign-sk", self.sign_sk),
+            ("management", self.management),
+        ] {
+            // Sent to the daemon in whole milliseconds
+            if timeout.is_some_and(|timeout| timeout.as_millis() == 0) {
+                bail!("{name} timeout must be at least 1ms");
+            }
+        }
+    }
+}
+
+impl Default for Certificates {
+    fn default() -> Self {
+        Self { hide_invalid: true }
+    }
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Self {
+            graceful: Duration::from_secs(1),
+            hard: Duration::from_secs(1),
+        }
+    }
+}
+
+impl Default for Logging {
+    fn default() -> Self {
+        Self {
+            filter: "info".to_owned(),
+            format: LogFormat::default(),
+            redact: false,
+        }
+    }
+}
+
+impl Config {
+    /// The config path used when none is explicitly specified
+    pub(crate) fn default_path() -> Option<PathBuf> {
+        std::env::var_os("XDG_CONFIG_HOME")
+            .filter(|dir| !dir.is_empty())
+            .map(PathBuf::from)
+            .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".config")))
+            .map(|dir| dir.join("sshagmux").join("config.toml"))
+    }
+
+    /// Loads the config from `path`, or from the default path if it exists
+    #[culpa::throws]
+    pub(crate) fn load(path: Option<&Path>) -> Self {
+        let path = match path {
+            Some(path) => path.to_owned(),
+            None => match Self::default_path() {
+                Some(path) if path.exists() => path,
+                _ => return Self::default(),
+            },
+        };
+        Self::load_from(&path).with_context(|| format!("failed to load config from {path:?}"))?
+    }
+
+    #[culpa::throws]
+    fn load_from(path: &Path) -> Self {
+        let contents = std::fs::read_to_string(path)?;
+        let mut config: Self = toml::from_str(&contents)?;
+        config.validate()?;
+        config
+    }
+
+    /// Expands environment variables in paths and checks for invalid values
+    #[culpa::throws]
+    fn validate(&mut self) {
+        if let Some(bind_address) = &mut self.daemon.bind_address {
+            *bind_address = PathBuf::from(
+                expand_path(&bind_address.to_string_lossy())
+                    .context("invalid daemon.bind-address")?,
+            );
+        }
+        if let Some(control_address) = &mut self.daemon.control_address {
+            *control_address = PathBuf::from(
+                expand_path(&control_address.to_string_lossy())
+                    .context("invalid daemon.control-address")?,
+            );
+        }
+
+        if let Some(path) = &mut self.audit.path {
+            *path =
+                PathBuf::from(expand_path(&path.to_string_lossy()).context("invalid audit.path")?);
+        }
+        if let Some(path) = &mut self.capture.path {
+            *path = PathBuf::from(
+                expand_path(&path.to_string_lossy()).context("invalid capture.path")?,
+            );
+        }
+
+        match &mut self.metrics.listen {
+            Some(MetricsAddress::Unix(path)) => {
+                *path = PathBuf::from(
+                    expand_path(&path.to_string_lossy()).context("invalid metrics.listen")?,
+                );
+            }
+            // The metrics include upstream paths and key usage, so aren't for sharing
+            Some(MetricsAddress::Tcp(addr)) if !addr.ip().is_loopback() => {
+                bail!("invalid metrics.listen, {addr} is not a loopback address");
+            }
+            Some(MetricsAddress::Tcp(_)) | None => {}
+        }
+
+        for (name, timeout) in [
+            ("timeouts.identities", self.timeouts.identities),
+            ("timeouts.sign", self.timeouts.sign),
+            ("timeouts.sign-sk", self.timeouts.sign_sk),
+            ("timeouts.management", self.timeouts.management),
+            ("shutdown.graceful", self.shutdown.graceful),
+            ("shutdown.hard", self.shutdown.hard),
+        ] {
+            if timeout.is_zero() {
+                bail!("{name} must be greater than zero");
+            }
+        }
+
+        if let Some(preferred) = &mut self.routing.preferred {
+            *preferred = expand_path(preferred).context("invalid routing.preferred")?;
+        }
+        self.routing
+            .adds
+            .validate()
+            .context("invalid routing.adds")?;
+        self.routing
+            .remove_all
+            .validate()
+            .context("invalid routing.remove-all")?;
+        if let Passthrough::Upstream(path) = &mut self.routing.unknown {
+            *path = expand_path(path)
+                .context(r#"expected "refuse", "preferred", "adds", "fan-out" or a path"#)
+                .context("invalid routing.unknown")?;
+        }
+        for (i, rule) in self.routing.add_rules.iter_mut().enumerate() {
+            rule.to
+                .validate()
+                .with_context(|| format!("invalid routing.add-rules[{i}].to"))?;
+        }
+
+        for (i, fingerprint) in self.confirm.fingerprints.iter().enumerate() {
+            if !fingerprint.starts_with("SHA256:") {
+                bail!("invalid confirm.fingerprints[{i}], expected a SHA256:... fingerprint");
+            }
+        }
+        for (i, path) in self.confirm.upstreams.iter_mut().enumerate() {
+            if path == KEYSTORE_PATH {
+                continue;
+            }
+            *path = expand_path(path).with_context(|| format!("invalid confirm.upstreams[{i}]"))?;
+        }
+
+        for (i, rule) in self.signing.rules.iter_mut().enumerate() {
+            if rule
+                .fingerprint
+                .as_ref()
+                .is_some_and(|fingerprint| !fingerprint.starts_with("SHA256:"))
+            {
+                bail!("invalid signing.rules[{i}].fingerprint, expected a SHA256:... fingerprint");
+            }
+            if !rule.allow && !rule.upstreams.is_empty() {
+                bail!("invalid signing.rules[{i}], upstreams can only be set when allow is true");
+            }
+            for (j, path) in rule.upstreams.iter_mut().enumerate() {
+                if path == KEYSTORE_PATH {
+                    continue;
+                }
+                *path = expand_path(path)
+                    .with_context(|| format!("invalid signing.rules[{i}].upstreams[{j}]"))?;
+            }
+        }
+
+        for (i, path) in self.destinations.known_hosts.iter_mut().enumerate() {
+            *path = PathBuf::from(
+                expand_path(&path.to_string_lossy())
+                    .with_context(|| format!("invalid destinations.known-hosts[{i}]"))?,
+            );
+        }
+        for (i, rule) in self.destinations.rules.iter_mut().enumerate() {
+            if rule
+                .fingerprint
+                .as_ref()
+                .is_some_and(|fingerprint| !fingerprint.starts_with("SHA256:"))
+            {
+                bail!(
+                    "invalid destinations.rules[{i}].fingerprint, expected a SHA256:... fingerprint"
+                );
+            }
+            if let Some(path) = &mut rule.upstream {
+                if path != KEYSTORE_PATH {
+                    *path = expand_path(path)
+                        .with_context(|| format!("invalid destinations.rules[{i}].upstream"))?;
+                }
+            }
+        }
+
+        EnvFilter::builder()
+            .parse(&self.logging.filter)
+            .context("invalid logging.filter")?;
+
+        for i in 0..self.upstreams.len() {
+            let path = expand_path(&self.upstreams[i].path)
+                .with_context(|| format!("invalid upstreams[{i}].path"))?;
+            if self.upstreams[..i]
+                .iter()
+                .any(|upstream| upstream.path == path)
+            {
+                bail!("upstreams[{i}].path {path:?} is duplicated");
+            }
+            self.upstreams[i]
+                .timeouts
+                .validate()
+                .with_context(|| format!("invalid upstreams[{i}].timeouts"))?;
+            self.upstreams[i].path = path;
+        }
+    }
+
+    pub(crate) fn preferred(&self) -> Option<Rc<str>> {
+        self.routing.preferred.as_deref().map(Rc::from)
+    }
+}
+
+impl Target {
+    /// Expands the path of a specific upstream target
+    #[culpa::throws]
+    fn validate(&mut self) {
+        if let Self::Upstream(path) = self {
+            *path = expand_path(path)
+                .context(r#"expected "latest", "broadcast", "keystore" or a path"#)?;
+        }
+    }
+}
+
+/// Matches `text` against `pattern`, where `*` in the pattern matches any sequence of characters
+pub(crate) fn glob_matches(pattern: &str, text: &str) -> bool {
+    match pattern.split_once('*') {
+        None => pattern == text,
+        Some((prefix, rest)) => {
+            let Some(text) = text.strip_prefix(prefix) else {
+                return false;
+            };
+            (0..=text.len())
+                .filter(|&i| text.is_char_boundary(i))
+                .any(|i| glob_matches(rest, &text[i..]))
+        }
+    }
+}
+
+/// Expands `~` and environment variables, and requires the result to be absolute so that it
+/// doesn't depend on the daemon's working directory
+#[culpa::throws]
+fn expand_path(path: &str) -> String {
+    let expanded = shellexpand::full(path).map_err(|e| eyre!("{e}"))?;
+    if !Path::new(expanded.as_ref()).is_absolute() {
+        bail!("path {expanded:?} is not absolute");
+    }
+    expanded.into_owned()
+}
+
+impl std::fmt::Display for LogFormat {
+    #[culpa::throws(std::fmt::Error)]
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) {
+        match self {
+            Self::Compact => write!(f, "compact")?,
+            Self::Json => write!(f, "json")?,
+            Self::Journald => write!(f, "journald")?,
+        }
+    }
+}
@@ -0,0 +1,215 @@
+use bytes::{Bytes, BytesMut};
+use eyre::{bail, eyre, Error};
+use secrecy::ExposeSecret as _;
+use std::fmt;
+use tokio_util::codec::Decoder as _;
+
+use crate::packets::{
+    self, Codec, ErrorMsg, Extension, Frame, Identity, Parse as _, Request, Response, SignData,
+    SSH_AGENT_RSA_SHA2_256, SSH_AGENT_RSA_SHA2_512,
+};
+
+/// Formats a message as a readable multi-line description, it's treated as a request or response
+/// based on its type as the two don't overlap
+pub(crate) struct Decoded<'a>(pub(crate) &'a Frame);
+
+/// Splits length-prefixed messages, as sent on an agent socket, calling `f` with each one, an
+/// incomplete message at the end is an error
+#[culpa::throws]
+pub(crate) fn each_frame(bytes: &[u8], mut f: impl FnMut(Frame)) {
+    let mut src = BytesMut::from(bytes);
+    let mut codec = Codec::<Frame, Frame>::new();
+    while let Some(frame) = codec.decode_eof(&mut src)? {
+        f(frame);
+    }
+}
+
+/// Parses hex bytes, ignoring any whitespace
+#[culpa::throws]
+pub(crate) fn hex(text: &str) -> Vec<u8> {
+    let digits: Vec<u8> = text.bytes().filter(|b| !b.is_ascii_whitespace()).collect();
+    if !digits.len().is_multiple_of(2) {
+        bail!("odd number of hex digits");
+    }
+    digits
+        .chunks(2)
+        .map(|pair| {
+            let pair = std::str::from_utf8(pair)?;
+            u8::from_str_radix(pair, 16).map_err(|e| eyre!("invalid hex {pair:?}: {e}"))
+        })
+        .collect::<Result<_, Error>>()?
+}
+
+fn key(blob: &Bytes) -> String {
+    format!(
+        "{} {}",
+        packets::key_type(blob).as_deref().unwrap_or("unknown"),
+        packets::fingerprint(blob)
+    )
+}
+
+#[culpa::throws(fmt::Error)]
+fn write_request(f: &mut fmt::Formatter<'_>, request: &Request) {
+    match request {
+        Request::Extension(extension) => write!(f, "request extension {}", extension.kind())?,
+        request => write!(f, "request {}", request.name())?,
+    }
+    match request {
+        Request::SignRequest { blob, data, flags } => {
+            write!(f, "\n  key {}", key(blob))?;
+            write!(f, "\n  data {} bytes", data.len())?;
+            match SignData::parse(data) {
+                Some(SignData::UserAuth {
+                    session_id,
+                    user,
+                    service,
+                    algorithm,
+                    key: auth_key,
+                    host_key,
+                }) => {
+                    write!(f, ", user authentication")?;
+                    write!(f, "\n    session id {} bytes", session_id.len())?;
+                    write!(f, "\n    user {user:?}")?;
+                    write!(f, "\n    service {service:?}")?;
+                    write!(f, "\n    algorithm {algorithm}")?;
+                    write!(f, "\n    key {}", key(&auth_key))?;
+                    if let Some(host_key) = host_key {
+                        write!(f, "\n    host key {}", key(&host_key))?;
+                    }
+                }
+                Some(SignData::SshSig {
+                    namespace,
+                    hash_algorithm,
+                    hash,
+                }) => {
+                    write!(f, ", SSHSIG")?;
+                    write!(f, "\n    namespace {namespace:?}")?;
+                    write!(f, "\n    hash {hash_algorithm}, {} bytes", hash.len())?;
+                }
+                None => {}
+            }
+            write!(f, "\n  flags {flags:#x}")?;
+            if flags & SSH_AGENT_RSA_SHA2_256 != 0 {
+                write!(f, " rsa-sha2-256")?;
+            }
+            if flags & SSH_AGENT_RSA_SHA2_512 != 0 {
+                write!(f, " rsa-sha2-512")?;
+            }
+        }
+        Request::AddIdentity { key_type, contents }
+        | Request::AddIdConstrained { key_type, contents } => {
+            let constrained = matches!(request, Request::AddIdConstrained { .. });
+            match Identity::parse(key_type, contents.expose_secret(), constrained) {
+                Ok(identity) => {
+                    match identity.public_blob() {
+                        Ok(blob) => write!(f, "\n  key {}", key(&blob))?,
+                        Err(_) => write!(f, "\n  key {key_type}")?,
+                    }
+                    write!(
+                        f,
+                        "\n  comment {:?}",
+                        String::from_utf8_lossy(&identity.comment)
+                    )?;
+                    for constraint in &identity.constraints {
+                        write!(f, "\n  constraint {constraint:?}")?;
+                    }
+                }
+                Err(e) => write!(f, "\n  key {key_type}, invalid: {e}")?,
+            }
+            write!(f, "\n  private key not shown")?;
+        }
+        Request::RemoveIdentity { blob } => write!(f, "\n  key {}", key(blob))?,
+        Request::Lock { .. } | Request::Unlock { .. } => write!(f, "\n  passphrase not shown")?,
+        Request::Extension(Extension::SessionBind(bind)) => {
+            write!(f, "\n  host key {}", key(&bind.host_key))?;
+            write!(f, "\n  session id {} bytes", bind.session_id.len())?;
+            write!(f, "\n  forwarding {}", bind.forwarding)?;
+        }
+        Request::Extension(
+            Extension::AddUpstreamV2(upstream) | Extension::AddUpstreamV3(upstream),
+        ) => {
+            write!(f, "\n  upstream {}", upstream.path)?;
+            write!(f, "\n  forward adds {}", upstream.forward_adds)?;
+            write!(f, "\n  timeouts {:?}", upstream.timeouts)?;
+        }
+        Request::Extension(Extension::RemoveUpstream(path) | Extension::SetPreferred(path)) => {
+            write!(f, "\n  upstream {path}")?
+        }
+        Request::Extension(Extension::Unknown { contents, .. }) => {
+            write!(f, "\n  {} bytes", contents.len())?
+        }
+        Request::RequestIdentities
+        | Request::RemoveAllIdentities
+        | Request::Extension(_)
+        | Request::Unknown { .. } => {}
+    }
+}
+
+#[culpa::throws(fmt::Error)]
+fn write_response(f: &mut fmt::Formatter<'_>, response: &Response) {
+    write!(f, "response {}", response.name())?;
+    match response {
+        Response::Identities { keys } => {
+            for public_key in keys {
+                write!(
+                    f,
+                    "\n  key {} {:?}",
+                    key(&public_key.blob),
+                    public_key.comment()
+                )?;
+                if let Some(certificate) = public_key.certificate() {
+                    write!(
+                        f,
+                        "\n    certificate {:?} of {} signed by CA {}",
+                        certificate.key_id,
+                        key(&certificate.key),
+                        certificate.ca_fingerprint
+                    )?;
+                }
+            }
+        }
+        Response::SignResponse { signature } => {
+            write!(
+                f,
+                "\n  algorithm {}",
+                packets::key_type(signature).as_deref().unwrap_or("unknown")
+            )?;
+            write!(f, "\n  signature {} bytes", signature.len())?;
+        }
+        Response::ExtensionFailure { contents } => {
+            match ErrorMsg::try_from(&mut contents.clone()).and_then(Error::try_from) {
+                Ok(error) => write!(f, "\n  error {error:#}")?,
+                Err(_) if contents.is_empty() => {}
+                Err(_) => write!(f, "\n  {} bytes", contents.len())?,
+            }
+        }
+        Response::Success { contents } | Response::Failure { contents } => {
+            if !contents.is_empty() {
+                write!(f, "\n  {} bytes", contents.len())?;
+            }
+        }
+        Response::Extension(_) | Response::Unknown { .. } => {}
+    }
+}
+
+impl fmt::Display for Decoded<'_> {
+    #[culpa::throws(fmt::Error)]
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) {
+        let Frame { kind, contents } = self.0;
+        match Request::parse(*kind, contents.clone()) {
+            Ok(Request::Unknown { .. }) if Request::carries_secrets(*kind) => {
+                return write!(f, "request type {kind}, smartcard PIN not shown")?
+            }
+            Ok(Request::Unknown { .. }) => {}
+            Ok(request) => return write_request(f, &request)?,
+            Err(e) => return write!(f, "invalid request type {kind}: {e}")?,
+        }
+        match Response::parse(*kind, contents.clone()) {
+            Ok(Response::Unknown { .. }) => {
+                write!(f, "unknown message type {kind}\n  {} bytes", contents.len())?
+            }
+            Ok(response) => write_response(f, &response)?,
+            Err(e) => write!(f, "invalid response type {kind}: {e}")?,
+        }
+    }
+}
@@ -0,0 +1,187 @@
+use eyre::{Error, WrapErr as _};
+use futures::stream::StreamExt as _;
+use indexmap::{IndexMap, IndexSet};
+use inotify::{Inotify, WatchMask, Watches};
+use std::{
+    os::unix::fs::{FileTypeExt as _, MetadataExt as _},
+    path::Path,
+    pin::pin,
+    rc::Rc,
+    time::Duration,
+};
+
+use crate::{app::Context, config::TimeoutOverrides, upstreams::Upstream};
+
+/// `sshd` creates forwarded agent sockets at `/tmp/ssh-XXXXXXXXXX/agent.<pid>`
+const SOCKET_DIR_PARENT: &str = "/tmp";
+const SOCKET_DIR_PREFIX: &str = "ssh-";
+const SOCKET_PREFIX: &str = "agent.";
+
+/// `sshd` binds the socket before it starts listening on it, so we may see it before it's usable
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+const MAX_ATTEMPTS: usize = 5;
+
+/// Watches for forwarded agent sockets owned by us, adding them as upstreams when they appear and
+/// removing them once they're gone
+#[culpa::throws]
+pub(crate) async fn run(context: Rc<Context>) {
+    let uid = nix::unistd::geteuid().as_raw();
+
+    let inotify = Inotify::init().context("failed to initialize inotify")?;
+    let mut watches = inotify.watches();
+    watches
+        .add(
+            SOCKET_DIR_PARENT,
+            WatchMask::CREATE | WatchMask::MOVED_TO | WatchMask::ONLYDIR,
+        )
+        .with_context(|| format!("failed to watch {SOCKET_DIR_PARENT}"))?;
+
+    let mut events = pin!(inotify
+        .into_event_stream([0; 1024])?
+        .take_until(context.shutdown.clone()));
+
+    let mut discovered = IndexSet::<Rc<str>>::new();
+    let mut attempts = IndexMap::<Rc<str>, usize>::new();
+
+    loop {
+        let sockets = scan(uid, &mut watches)?;
+
+        for path in discovered.clone() {
+            if !sockets.contains(&path) {
+                discovered.shift_remove(&path);
+                if context.upstreams.remove(&path, "socket vanished") {
+                    tracing::info!(%path, "removed vanished upstream");
+                }
+            }
+        }
+        attempts.retain(|path, _| sockets.contains(path));
+
+        for path in sockets {
+            if discovered.contains(&path) {
+                continue;
+            }
+            let attempt = attempts.entry(path.clone()).or_default();
+            if *attempt >= MAX_ATTEMPTS {
+                continue;
+            }
+            *attempt += 1;
+            let attempt = *attempt;
+
+            tracing::info!(%path, attempt, "adding discovered upstream");
+            match context
+                .add_upstream(Upstream {
+                    path: path.clone(),
+                    forward_adds: false,
+                    timeouts: TimeoutOverrides::default(),
+                })
+                .await
+            {
+                Ok(()) => {
+                    attempts.shift_remove(&path);
+                    discovered.insert(path);
+                }
+                Err(e) if attempt < MAX_ATTEMPTS => {
+                    tracing::debug!(%path, "failed to add discovered upstream, will retry: {e:?}");
+                }
+                Err(e) => {
+                    tracing::warn!(%path, "failed to add discovered upstream: {e:?}");
+                }
+            }
+        }
+
+        let event = if attempts.values().any(|&attempt| attempt < MAX_ATTEMPTS) {
+            match tokio::time::timeout(RETRY_DELAY, events.next()).await {
+                Ok(event) => event,
+                Err(_) => continue,
+            }
+        } else {
+            events.next().await
+        };
+
+        match event {
+            Some(event) => {
+                event.context("failed to read inotify event")?;
+            }
+            None => break,
+        }
+    }
+}
+
+/// Finds all candidate sockets, adding watches on any new socket directories
+#[culpa::throws]
+fn scan(uid: u32, watches: &mut Watches) -> IndexSet<Rc<str>> {
+    let mut sockets = IndexSet::new();
+
+    for entry in std::fs::read_dir(SOCKET_DIR_PARENT)? {
+        // Directories come and go as sessions start and end
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(e) => {
+                tracing::debug!("failed to read {SOCKET_DIR_PARENT} entry: {e:?}");
+                continue;
+            }
+        };
+        if !entry
+            .file_name()
+            .to_str()
+            .is_some_and(|name| name.starts_with(SOCKET_DIR_PREFIX))
+        {
+            continue;
+        }
+
+        let dir = entry.path();
+        match entry.metadata() {
+            Ok(metadata) if metadata.is_dir() && metadata.uid() == uid => {}
+            _ => continue,
+        }
+
+        if let Err(e) = watches.add(
+            &dir,
+            WatchMask::CREATE | WatchMask::DELETE | WatchMask::MOVED_TO | WatchMask::MOVED_FROM,
+        ) {
+            // Most likely the directory was removed since we listed it
+            tracing::debug!(dir = %dir.display(), "failed to watch socket directory: {e:?}");
+            continue;
+        }
+
+        sockets.extend(scan_dir(uid, &dir).unwrap_or_else(|e| {
+            tracing::debug!(dir = %dir.display(), "failed to scan socket directory: {e:?}");
+            Vec::new()
+        }));
+    }
+
+    sockets
+}
+
+#[culpa::throws]
+fn scan_dir(uid: u32, dir: &Path) -> Vec<Rc<str>> {
+    let mut sockets = Vec::new();
+
+    for entry in std::fs::read_dir(dir)? {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(e) => {
+                tracing::debug!(dir = %dir.display(), "failed to read socket directory entry: {e:?}");
+                continue;
+            }
+        };
+        if !entry
+            .file_name()
+            .to_str()
+            .is_some_and(|name| name.starts_with(SOCKET_PREFIX))
+        {
+            continue;
+        }
+
+        match entry.metadata() {
+            Ok(metadata) if metadata.file_type().is_socket() && metadata.uid() == uid => {}
+            _ => continue,
+        }
+
+        if let Some(path) = entry.path().to_str() {
+            sockets.push(Rc::from(path));
+        }
+    }
+
+    sockets
+}
@@ -0,0 +1,258 @@
+use bytes::Bytes;
+use eyre::{bail, eyre, Error};
+use indexmap::IndexMap;
+use secrecy::{ExposeSecret, SecretBytesMut};
+use sha2::{Sha256, Sha512};
+use signature::{SignatureEncoding as _, Signer as _};
+use ssh_key::{
+    private::{KeypairData, RsaKeypair},
+    Algorithm, HashAlg, Mpint, Signature,
+};
+use std::{cell::RefCell, time::Instant};
+
+use crate::{
+    askpass,
+    packets::{
+        self, Constraint, Identity, PublicKey, Request, Response, SSH_AGENT_RSA_SHA2_256,
+        SSH_AGENT_RSA_SHA2_512,
+    },
+};
+
+/// The name used in place of a socket path for the keystore upstream
+pub(crate) const KEYSTORE_PATH: &str = "<keystore>";
+
+/// A software agent inside the daemon, holding identities added to it in memory
+#[derive(Debug, Default)]
+pub(crate) struct KeyStore {
+    keys: RefCell<IndexMap<Bytes, Key>>,
+    /// A digest of the passphrase it was locked with, while it is locked
+    lock: RefCell<Option<[u8; 32]>>,
+}
+
+#[derive(Debug)]
+struct Key {
+    key_type: String,
+    /// The contents of the add identity request, only decoded when signing
+    secret: SecretBytesMut,
+    comment: Bytes,
+    expires: Option<Instant>,
+    confirm: bool,
+}
+
+impl KeyStore {
+    /// Answers a request the same way an upstream agent would
+    pub(crate) async fn handle(&self, request: Request) -> Response {
+        self.remove_expired();
+        // The same as `ssh-agent`, identities are hidden and unusable until unlocked
+        let locked = *self.lock.borrow();
+        if let Some(lock) = locked {
+            return match request {
+                Request::Unlock { passphrase } if digest(passphrase.expose_secret()) == lock => {
+                    tracing::info!("unlocked keystore");
+                    *self.lock.borrow_mut() = None;
+                    Response::SUCCESS
+                }
+                Request::RequestIdentities => Response::Identities { keys: Vec::new() },
+                request => {
+                    tracing::info!(
+                        kind = request.kind(),
+                        "keystore is locked, refusing request"
+                    );
+                    Response::FAILURE
+                }
+            };
+        }
+        match request {
+            Request::RequestIdentities => Response::Identities {
+                keys: self
+                    .keys
+                    .borrow()
+                    .iter()
+                    .map(|(blob, key)| PublicKey {
+                        blob: blob.clone(),
+                        comment: key.comment.clone(),
+                    })
+                    .collect(),
+            },
+            Request::SignRequest { blob, data, flags } => {
+                match self.sign(&blob, &data, flags).await {
+                    Ok(Some(signature)) => Response::SignResponse { signature },
+                    Ok(None) => Response::FAILURE,
+                    Err(e) => {
+                        tracing::warn!(
+                            fingerprint = packets::fingerprint(&blob),
+                            "failed to sign with keystore identity: {e:?}"
+                        );
+                        Response::FAILURE
+                    }
+                }
+            }
+            Request::AddIdentity { key_type, contents } => self.add(key_type, contents, false),
+            Request::AddIdConstrained { key_type, contents } => self.add(key_type, contents, true),
+            Request::RemoveIdentity { blob } => {
+                if self.keys.borrow_mut().shift_remove(&blob).is_some() {
+                    tracing::info!(
+                        fingerprint = packets::fingerprint(&blob),
+                        "removed keystore identity"
+                    );
+                    Response::SUCCESS
+                } else {
+                    Response::FAILURE
+                }
+            }
+            Request::RemoveAllIdentities => {
+                self.keys.borrow_mut().clear();
+                tracing::info!("removed all keystore identities");
+                Response::SUCCESS
+            }
+            Request::Lock { passphrase } => {
+                *self.lock.borrow_mut() = Some(digest(passphrase.expose_secret()));
+                tracing::info!("locked keystore");
+                Response::SUCCESS
+            }
+            request => {
+                tracing::debug!(kind = request.kind(), "unsupported keystore request");
+                Response::FAILURE
+            }
+        }
+    }
+
+    /// Expired identities are dropped lazily whenever the keystore is next used
+    fn remove_expired(&self) {
+        let now = Instant::now();
+        self.keys.borrow_mut().retain(|blob, key| {
+            let expired = key.expires.is_some_and(|expires| expires <= now);
+            if expired {
+                tracing::info!(
+                    fingerprint = packets::fingerprint(blob),
+                    "keystore identity lifetime expired"
+                );
+            }
+            !expired
+        });
+    }
+
+    fn add(&self, key_type: String, secret: SecretBytesMut, constrained: bool) -> Response {
+        let identity = match Identity::parse(&key_type, secret.expose_secret(), constrained) {
+            Ok(identity) => identity,
+            Err(e) => {
+                tracing::warn!(%key_type, "refusing to add keystore identity: {e:?}");
+                return Response::FAILURE;
+            }
+        };
+        let blob = match identity.public_blob() {
+            Ok(blob) => blob,
+            Err(e) => {
+                tracing::warn!(%key_type, "refusing to add keystore identity: {e:?}");
+                return Response::FAILURE;
+            }
+        };
+
+        let mut key = Key {
+            key_type,
+            secret,
+            comment: identity.comment,
+            expires: None,
+            confirm: false,
+        };
+        for constraint in identity.constraints {
+            match constraint {
+                Constraint::Lifetime(lifetime) => key.expires = Some(Instant::now() + lifetime),
+                Constraint::Confirm => key.confirm = true,
+            }
+        }
+
+        tracing::info!(
+            fingerprint = packets::fingerprint(&blob),
+            key.key_type,
+            ?key.expires,
+            key.confirm,
+            "added keystore identity"
+        );
+        // Re-adding an identity replaces its constraints, the same as `ssh-agent`
+        self.keys.borrow_mut().insert(blob, key);
+        Response::SUCCESS
+    }
+
+    /// Returns `None` if we don't hold the identity, or the user declined to use it
+    #[culpa::throws]
+    async fn sign(&self, blob: &Bytes, data: &[u8], flags: u32) -> Option<Bytes> {
+        let Some((key_type, secret, comment, confirm)) = self.keys.borrow().get(blob).map(|key| {
+            (
+                key.key_type.clone(),
+                key.secret.clone(),
+                key.comment.clone(),
+                key.confirm,
+            )
+        }) else {
+            return None;
+        };
+
+        if confirm {
+            let prompt = format!(
+                "Allow use of key {}?\nKey fingerprint {}.",
+                String::from_utf8_lossy(&comment),
+                packets::fingerprint(blob),
+            );
+            if !askpass::confirm(&prompt).await? {
+                tracing::info!(
+                    fingerprint = packets::fingerprint(blob),
+                    "use of keystore identity declined"
+                );
+                return None;
+            }
+        }
+
+        let algorithm = Algorithm::new(&key_type)?;
+        let keypair = KeypairData::decode_as(&mut secret.expose_secret().as_ref(), algorithm)?;
+        let signature = match &keypair {
+            KeypairData::Rsa(keypair) if flags & SSH_AGENT_RSA_SHA2_512 != 0 => {
+                let key = rsa::pkcs1v15::SigningKey::<Sha512>::new(rsa_private_key(keypair)?);
+                Signature::new(
+                    Algorithm::Rsa {
+                        hash: Some(HashAlg::Sha512),
+                    },
+                    key.try_sign(data)?.to_vec(),
+                )?
+            }
+            KeypairData::Rsa(keypair) if flags & SSH_AGENT_RSA_SHA2_256 != 0 => {
+                let key = rsa::pkcs1v15::SigningKey::<Sha256>::new(rsa_private_key(keypair)?);
+                Signature::new(
+                    Algorithm::Rsa {
+                        hash: Some(HashAlg::Sha256),
+                    },
+                    key.try_sign(data)?.to_vec(),
+                )?
+            }
+            KeypairData::Rsa(_) => bail!("refusing to create a SHA-1 ssh-rsa signature"),
+            keypair => keypair.try_sign(data)?,
+        };
+
+        Some(Bytes::from(Vec::<u8>::try_from(signature)?))
+    }
+}
+
+/// `ssh-key`'s own conversion passes `p` twice instead of `p` and `q`, so always fails
+#[culpa::throws]
+fn rsa_private_key(keypair: &RsaKeypair) -> rsa::RsaPrivateKey {
+    let uint = |mpint: &Mpint| {
+        mpint
+            .as_positive_bytes()
+            .map(rsa::BigUint::from_bytes_be)
+            .ok_or_else(|| eyre!("invalid rsa key component"))
+    };
+    rsa::RsaPrivateKey::from_components(
+        uint(&keypair.public.n)?,
+        uint(&keypair.public.e)?,
+        uint(&keypair.private.d)?,
+        vec![uint(&keypair.private.p)?, uint(&keypair.private.q)?],
+    )?
+}
+
+/// Only a digest of the lock passphrase is kept, comparing digests also means the time taken
+/// doesn't depend on how much of the passphrase matches
+fn digest(passphrase: &[u8]) -> [u8; 32] {
+    use sha2::Digest as _;
+
+    Sha256::digest(passphrase).into()
+}
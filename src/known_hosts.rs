@@ -0,0 +1,81 @@
+use ssh_key::known_hosts::{HostPatterns, KnownHosts};
+use std::path::{Path, PathBuf};
+
+use crate::packets;
+
+/// A host that a connection is logging in to, identified by the host key `ssh` sent with
+/// `session-bind@openssh.com`
+#[derive(Debug, Clone)]
+pub(crate) struct Host {
+    /// The host key's `SHA256:...` fingerprint
+    pub(crate) fingerprint: String,
+    /// The names that known hosts files list for the host key, without any port
+    pub(crate) names: Vec<String>,
+}
+
+/// The files `ssh` reads by default, used when none are configured
+pub(crate) fn default_files() -> Vec<PathBuf> {
+    std::env::var_os("HOME")
+        .map(|home| Path::new(&home).join(".ssh").join("known_hosts"))
+        .into_iter()
+        .chain([PathBuf::from("/etc/ssh/ssh_known_hosts")])
+        .collect()
+}
+
+impl Host {
+    /// Looks up the names of the host with this key, they're read again every time as `ssh` adds
+    /// new hosts just before logging in to them
+    pub(crate) fn lookup(fingerprint: String, files: &[PathBuf]) -> Self {
+        let mut names = Vec::new();
+        for path in files {
+            let contents = match std::fs::read_to_string(path) {
+                Ok(contents) => contents,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(e) => {
+                    tracing::warn!(?path, "failed to read known hosts: {e}");
+                    continue;
+                }
+            };
+            // Lines with unsupported key types fail to parse, they can't be this host anyway
+            for entry in KnownHosts::new(&contents).filter_map(Result::ok) {
+                // Revoked keys and certificate authorities don't name the host key itself
+                if entry.marker().is_some() {
+                    continue;
+                }
+                let Ok(blob) = entry.public_key().to_bytes() else {
+                    continue;
+                };
+                if packets::fingerprint(&blob) != fingerprint {
+                    continue;
+                }
+                // Hashed names can only be checked against a known name, not a pattern
+                let HostPatterns::Patterns(patterns) = entry.host_patterns() else {
+                    continue;
+                };
+                for pattern in patterns {
+                    if pattern.starts_with('!') {
+                        continue;
+                    }
+                    let name = match pattern.strip_prefix('[').and_then(|p| p.split_once("]:")) {
+                        Some((name, _port)) => name,
+                        None => pattern,
+                    };
+                    if !names.iter().any(|known| known == name) {
+                        names.push(name.to_owned());
+                    }
+                }
+            }
+        }
+        Self { fingerprint, names }
+    }
+}
+
+impl std::fmt::Display for Host {
+    #[culpa::throws(std::fmt::Error)]
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) {
+        match self.names.first() {
+            Some(name) => write!(f, "{name} ({})", self.fingerprint)?,
+            None => write!(f, "{}", self.fingerprint)?,
+        }
+    }
+}
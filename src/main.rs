@@ -1,71 +1,115 @@
 use clap::Parser;
-use eyre::{eyre, Error};
+use eyre::{eyre, Error, WrapErr as _};
 use futures::future::{AbortHandle, Abortable, Aborted, FutureExt as _, TryFutureExt as _};
+use signal_hook::{
+    consts::{SIGINT, SIGTERM},
+    iterator::Signals,
+};
 use std::rc::Rc;
-use tracing_subscriber::{filter::LevelFilter, layer::SubscriberExt, EnvFilter};
+use tracing_subscriber::{
+    filter::LevelFilter,
+    fmt,
+    layer::{Layer as _, SubscriberExt as _},
+    EnvFilter,
+};
+
+use crate::config::LogFormat;
 
 mod app;
+mod askpass;
+mod audit;
+mod capture;
 mod client;
+mod config;
+mod decode;
+mod discovery;
 mod error;
+mod keystore;
+mod known_hosts;
+mod metrics;
+mod monitor;
 mod net;
 mod packets;
 mod server;
+mod top;
 mod upstreams;
+mod verify;
 
 #[culpa::throws]
 fn main() {
     color_eyre::install()?;
 
+    let app = app::App::parse();
+    let config = app.config()?;
+
+    let filter = if std::env::var_os("SSHAGMUX_LOG").is_some() {
+        EnvFilter::builder()
+            .with_default_directive(LevelFilter::INFO.into())
+            .with_env_var("SSHAGMUX_LOG")
+            .from_env()?
+    } else {
+        EnvFilter::builder().parse(&config.logging.filter)?
+    };
+
+    let logging = app.logging(&config);
+    packets::redact_logs(logging.redact);
+    let format = match logging.format {
+        LogFormat::Compact => fmt::layer().with_writer(std::io::stderr).compact().boxed(),
+        LogFormat::Json => fmt::layer().with_writer(std::io::stderr).json().boxed(),
+        LogFormat::Journald => tracing_journald::layer()
+            .context("failed to connect to journald")?
+            .boxed(),
+    };
+
     tracing::subscriber::set_global_default(
-        tracing_subscriber::fmt()
-            .with_env_filter(
-                EnvFilter::builder()
-                    .with_default_directive(LevelFilter::INFO.into())
-                    .with_env_var("SSHAGMUX_LOG")
-                    .from_env()?,
-            )
-            .with_writer(std::io::stderr)
-            .compact()
-            .finish()
+        tracing_subscriber::registry()
+            .with(format)
+            .with(filter)
             .with(tracing_error::ErrorLayer::default()),
     )?;
 
+    let shutdown = config.shutdown;
     let (handle1, reg1) = AbortHandle::new_pair();
     let (handle2, reg2) = AbortHandle::new_pair();
 
-    ctrlc::set_handler(move || {
-        if !handle1.is_aborted() {
-            tracing::info!("initial SIGINT, shutdown requested");
-            handle1.abort();
-            std::thread::spawn({
-                let handle2 = handle2.clone();
-                move || {
-                    std::thread::sleep(std::time::Duration::from_secs(1));
-                    tracing::warn!("shutdown timeout, hard shutdown requested");
-                    handle2.abort();
-                    std::thread::sleep(std::time::Duration::from_secs(1));
-                    tracing::error!("shutdown timeout, exiting");
-                    std::process::exit(1);
-                }
-            });
-        } else if !handle2.is_aborted() {
-            tracing::warn!("repeat SIGINT, hard shutdown requested");
-            handle2.abort();
-        } else {
-            tracing::error!("repeat SIGINT, exiting");
-            std::process::exit(1);
+    // SIGHUP is left for the daemon to handle
+    let mut signals = Signals::new([SIGINT, SIGTERM])?;
+    std::thread::spawn(move || {
+        for _ in signals.forever() {
+            if !handle1.is_aborted() {
+                tracing::info!("initial SIGINT, shutdown requested");
+                handle1.abort();
+                std::thread::spawn({
+                    let handle2 = handle2.clone();
+                    move || {
+                        std::thread::sleep(shutdown.graceful);
+                        tracing::warn!("shutdown timeout, hard shutdown requested");
+                        handle2.abort();
+                        std::thread::sleep(shutdown.hard);
+                        tracing::error!("shutdown timeout, exiting");
+                        std::process::exit(1);
+                    }
+                });
+            } else if !handle2.is_aborted() {
+                tracing::warn!("repeat SIGINT, hard shutdown requested");
+                handle2.abort();
+            } else {
+                tracing::error!("repeat SIGINT, exiting");
+                std::process::exit(1);
+            }
         }
-    })?;
+    });
 
     let context = Rc::new(app::Context::new(
         Abortable::new(futures::future::pending::<()>(), reg1).map(|_| ()),
+        config,
     ));
 
     tokio::runtime::Builder::new_current_thread()
         .enable_all()
         .build()?
         .block_on(
-            Abortable::new(app::App::parse().run(context), reg2)
+            Abortable::new(app.run(context), reg2)
                 .map_err(|Aborted| eyre!("clean shutdown failed")),
         )??;
 }
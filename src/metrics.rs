@@ -0,0 +1,313 @@
+use eyre::{bail, Error};
+use futures::stream::{StreamExt as _, TryStreamExt as _};
+use indexmap::IndexMap;
+use std::{cell::RefCell, fmt::Write as _, rc::Rc, time::Duration};
+use tokio::io::{AsyncRead, AsyncReadExt as _, AsyncWrite, AsyncWriteExt as _};
+
+use crate::{app::Context, config::MetricsAddress, net};
+
+/// Upper bounds of the latency histogram buckets, in seconds
+const BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// How long a scraper gets to send its request before we give up on it
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Kind {
+    Counter,
+    Gauge,
+    Histogram,
+}
+
+/// A named metric along with all of its labelled values
+#[derive(Debug)]
+pub(crate) struct Family {
+    pub(crate) name: String,
+    pub(crate) help: String,
+    pub(crate) kind: Kind,
+    pub(crate) samples: Vec<Sample>,
+}
+
+#[derive(Debug)]
+pub(crate) struct Sample {
+    /// Appended to the family name, histograms have `_bucket`, `_sum` and `_count` samples
+    pub(crate) suffix: String,
+    pub(crate) labels: Vec<(String, String)>,
+    pub(crate) value: f64,
+}
+
+#[derive(Debug, Default)]
+struct Histogram {
+    /// Not cumulative, the count of observations in each bucket of `BUCKETS` then `+Inf`
+    buckets: [u64; BUCKETS.len() + 1],
+    sum: f64,
+    count: u64,
+}
+
+/// Counters of what the daemon has done since it started
+#[derive(Debug, Default)]
+pub(crate) struct Metrics {
+    requests: RefCell<IndexMap<&'static str, u64>>,
+    signs: RefCell<IndexMap<(Rc<str>, &'static str), u64>>,
+    identities_latency: RefCell<Histogram>,
+    timeouts: RefCell<IndexMap<Rc<str>, u64>>,
+    upstreams_removed: RefCell<IndexMap<String, u64>>,
+}
+
+enum Listener {
+    Unix(net::UnixListener),
+    Tcp(tokio::net::TcpListener),
+}
+
+impl Histogram {
+    fn observe(&mut self, value: f64) {
+        let bucket = BUCKETS
+            .iter()
+            .position(|&bound| value <= bound)
+            .unwrap_or(BUCKETS.len());
+        self.buckets[bucket] += 1;
+        self.sum += value;
+        self.count += 1;
+    }
+
+    fn samples(&self) -> Vec<Sample> {
+        let bounds = BUCKETS
+            .iter()
+            .map(|bound| bound.to_string())
+            .chain(["+Inf".to_owned()]);
+        let mut cumulative = 0;
+        let mut samples: Vec<_> = bounds
+            .zip(self.buckets)
+            .map(|(bound, count)| {
+                cumulative += count;
+                Sample {
+                    suffix: "_bucket".to_owned(),
+                    labels: vec![("le".to_owned(), bound)],
+                    value: cumulative as f64,
+                }
+            })
+            .collect();
+        samples.push(Sample {
+            suffix: "_sum".to_owned(),
+            labels: Vec::new(),
+            value: self.sum,
+        });
+        samples.push(Sample {
+            suffix: "_count".to_owned(),
+            labels: Vec::new(),
+            value: self.count as f64,
+        });
+        samples
+    }
+}
+
+impl Metrics {
+    /// `kind` is the name of the request's message type
+    pub(crate) fn request(&self, kind: &'static str) {
+        *self.requests.borrow_mut().entry(kind).or_default() += 1;
+    }
+
+    /// `outcome` is one of `success`, `refused`, `invalid` or `error`
+    pub(crate) fn sign(&self, upstream: &Rc<str>, outcome: &'static str) {
+        *self
+            .signs
+            .borrow_mut()
+            .entry((upstream.clone(), outcome))
+            .or_default() += 1;
+    }
+
+    /// How long it took to get the identities from all upstreams
+    pub(crate) fn identities_latency(&self, latency: Duration) {
+        self.identities_latency
+            .borrow_mut()
+            .observe(latency.as_secs_f64());
+    }
+
+    pub(crate) fn timeout(&self, upstream: &Rc<str>) {
+        *self
+            .timeouts
+            .borrow_mut()
+            .entry(upstream.clone())
+            .or_default() += 1;
+    }
+
+    pub(crate) fn upstream_removed(&self, reason: &str) {
+        *self
+            .upstreams_removed
+            .borrow_mut()
+            .entry(reason.to_owned())
+            .or_default() += 1;
+    }
+
+    /// A snapshot of all the counters
+    pub(crate) fn families(&self) -> Vec<Family> {
+        let counter = |name: &str, help: &str, samples| Family {
+            name: name.to_owned(),
+            help: help.to_owned(),
+            kind: Kind::Counter,
+            samples,
+        };
+        let sample = |labels: &[(&str, &str)], value: u64| Sample {
+            suffix: String::new(),
+            labels: labels
+                .iter()
+                .map(|&(name, value)| (name.to_owned(), value.to_owned()))
+                .collect(),
+            value: value as f64,
+        };
+
+        vec![
+            counter(
+                "sshagmux_requests_total",
+                "Requests received from clients, by message type",
+                self.requests
+                    .borrow()
+                    .iter()
+                    .map(|(kind, &count)| sample(&[("type", kind)], count))
+                    .collect(),
+            ),
+            counter(
+                "sshagmux_upstream_signs_total",
+                "Sign requests sent to each upstream, by whether it signed, refused, sent an invalid signature or errored",
+                self.signs
+                    .borrow()
+                    .iter()
+                    .map(|((upstream, outcome), &count)| {
+                        sample(&[("upstream", upstream), ("outcome", outcome)], count)
+                    })
+                    .collect(),
+            ),
+            Family {
+                name: "sshagmux_identities_latency_seconds".to_owned(),
+                help: "How long identities requests took to fan out to all upstreams".to_owned(),
+                kind: Kind::Histogram,
+                samples: self.identities_latency.borrow().samples(),
+            },
+            counter(
+                "sshagmux_upstream_timeouts_total",
+                "Requests to each upstream that timed out",
+                self.timeouts
+                    .borrow()
+                    .iter()
+                    .map(|(upstream, &count)| sample(&[("upstream", upstream)], count))
+                    .collect(),
+            ),
+            counter(
+                "sshagmux_upstreams_removed_total",
+                "Upstreams that have been removed, by why they were removed",
+                self.upstreams_removed
+                    .borrow()
+                    .iter()
+                    .map(|(reason, &count)| sample(&[("reason", reason)], count))
+                    .collect(),
+            ),
+        ]
+    }
+}
+
+/// Renders `families` in the Prometheus text exposition format
+pub(crate) fn render(families: &[Family]) -> String {
+    let mut out = String::new();
+    for family in families {
+        let kind = match family.kind {
+            Kind::Counter => "counter",
+            Kind::Gauge => "gauge",
+            Kind::Histogram => "histogram",
+        };
+        let _ = writeln!(out, "# HELP {} {}", family.name, family.help);
+        let _ = writeln!(out, "# TYPE {} {kind}", family.name);
+        for sample in &family.samples {
+            out += &family.name;
+            out += &sample.suffix;
+            if !sample.labels.is_empty() {
+                let labels = sample
+                    .labels
+                    .iter()
+                    .map(|(name, value)| {
+                        let value = value
+                            .replace('\\', "\\\\")
+                            .replace('"', "\\\"")
+                            .replace('\n', "\\n");
+                        format!("{name}=\"{value}\"")
+                    })
+                    .collect::<Vec<_>>();
+                let _ = write!(out, "{{{}}}", labels.join(","));
+            }
+            let _ = writeln!(out, " {}", sample.value);
+        }
+    }
+    out
+}
+
+/// Serves the metrics over HTTP at `address` until shutdown
+#[culpa::throws]
+pub(crate) async fn serve(address: &MetricsAddress, context: Rc<Context>) {
+    let listener = match address {
+        MetricsAddress::Unix(path) => Listener::Unix(net::UnixListener::bind_private(path)?),
+        MetricsAddress::Tcp(addr) => Listener::Tcp(tokio::net::TcpListener::bind(addr).await?),
+    };
+    tracing::info!(?address, "serving metrics");
+
+    match listener {
+        Listener::Unix(mut listener) => {
+            listener
+                .incoming()
+                .take_until(context.shutdown.clone())
+                .map_ok(|(stream, _addr)| stream)
+                .try_for_each_concurrent(None, |stream| scrape(stream, &context))
+                .await?;
+            listener.close()?;
+        }
+        Listener::Tcp(listener) => {
+            futures::stream::poll_fn(|cx| listener.poll_accept(cx).map(Some))
+                .take_until(context.shutdown.clone())
+                .map_ok(|(stream, _addr)| stream)
+                .map_err(Error::from)
+                .try_for_each_concurrent(None, |stream| scrape(stream, &context))
+                .await?;
+        }
+    }
+}
+
+/// Errors from scrapers aren't a problem for the daemon, so are only logged
+#[culpa::throws]
+async fn scrape(stream: impl AsyncRead + AsyncWrite + Unpin, context: &Context) {
+    if let Err(e) = respond(stream, context).await {
+        tracing::debug!("failed to serve metrics: {e:?}");
+    }
+}
+
+/// Answers a single HTTP request, any path other than `/metrics` (or `/`) is not found
+#[culpa::throws]
+async fn respond(mut stream: impl AsyncRead + AsyncWrite + Unpin, context: &Context) {
+    let mut request = Vec::new();
+    let mut buffer = [0; 1024];
+    while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+        let read = tokio::time::timeout(REQUEST_TIMEOUT, stream.read(&mut buffer)).await??;
+        if read == 0 {
+            bail!("connection closed before end of request");
+        }
+        request.extend_from_slice(&buffer[..read]);
+        if request.len() > 16 * 1024 {
+            bail!("request too large");
+        }
+    }
+
+    let line = request.split(|&b| b == b'\r').next().unwrap_or_default();
+    let mut parts = line.split(|&b| b == b' ');
+    let (method, path) = (parts.next(), parts.next());
+    let (status, body) = match (method, path) {
+        (Some(b"GET"), Some(b"/metrics" | b"/")) => ("200 OK", render(&context.stats())),
+        (Some(b"GET"), _) => ("404 Not Found", "not found\n".to_owned()),
+        _ => ("405 Method Not Allowed", "method not allowed\n".to_owned()),
+    };
+
+    let response = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len(),
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await?;
+}
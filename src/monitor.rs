@@ -0,0 +1,158 @@
+use std::{
+    rc::Rc,
+    time::{Duration, SystemTime},
+};
+use tokio::sync::broadcast;
+
+/// How many events a slow subscriber can fall behind by before it misses some
+const CAPACITY: usize = 256;
+
+/// Something that happened in the daemon, streamed live to `sshagmux monitor`
+#[derive(Debug, Clone)]
+pub(crate) struct Event {
+    pub(crate) time: SystemTime,
+    pub(crate) kind: EventKind,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) enum EventKind {
+    ConnectionOpened {
+        connection: u64,
+        uid: u32,
+        pid: Option<i32>,
+        exe: Option<String>,
+    },
+    ConnectionClosed {
+        connection: u64,
+    },
+    Request {
+        connection: u64,
+        /// The message type name as used in the metrics, or the extension name for extensions
+        kind: String,
+        /// The key a sign or remove identity request is for
+        fingerprint: Option<String>,
+    },
+    /// The answer to the connection's last request has been sent
+    Responded {
+        connection: u64,
+        success: bool,
+    },
+    /// A sign request has been forwarded to an upstream, and we're waiting for its answer
+    SignSent {
+        upstream: Rc<str>,
+        fingerprint: String,
+    },
+    SignAnswered {
+        upstream: Rc<str>,
+        fingerprint: String,
+        /// One of `success`, `refused`, `invalid` or `error`
+        outcome: String,
+        elapsed: Duration,
+    },
+    UpstreamAdded {
+        path: Rc<str>,
+    },
+    UpstreamRemoved {
+        path: Rc<str>,
+        reason: String,
+    },
+    Timeout {
+        upstream: Rc<str>,
+    },
+    /// The subscriber fell behind and these events were dropped
+    Missed {
+        count: u64,
+    },
+}
+
+/// Sends events to everyone currently subscribed, events are dropped if no-one is
+#[derive(Debug)]
+pub(crate) struct Events {
+    sender: broadcast::Sender<Event>,
+}
+
+impl Default for Events {
+    fn default() -> Self {
+        Self {
+            sender: broadcast::channel(CAPACITY).0,
+        }
+    }
+}
+
+impl Events {
+    pub(crate) fn emit(&self, kind: EventKind) {
+        let _ = self.sender.send(Event {
+            time: SystemTime::now(),
+            kind,
+        });
+    }
+
+    pub(crate) fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.sender.subscribe()
+    }
+}
+
+impl std::fmt::Display for Event {
+    #[culpa::throws(std::fmt::Error)]
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) {
+        write!(f, "{} ", humantime::format_rfc3339_millis(self.time))?;
+        match &self.kind {
+            EventKind::ConnectionOpened {
+                connection,
+                uid,
+                pid,
+                exe,
+            } => {
+                write!(f, "[{connection}] connection opened by ")?;
+                write!(f, "{}", exe.as_deref().unwrap_or("unknown process"))?;
+                match pid {
+                    Some(pid) => write!(f, " (pid {pid}, uid {uid})")?,
+                    None => write!(f, " (uid {uid})")?,
+                }
+            }
+            EventKind::ConnectionClosed { connection } => {
+                write!(f, "[{connection}] connection closed")?
+            }
+            EventKind::Request {
+                connection,
+                kind,
+                fingerprint,
+            } => {
+                write!(f, "[{connection}] {kind}")?;
+                if let Some(fingerprint) = fingerprint {
+                    write!(f, " {fingerprint}")?;
+                }
+            }
+            EventKind::Responded {
+                connection,
+                success,
+            } => write!(
+                f,
+                "[{connection}] answered with {}",
+                if *success { "success" } else { "failure" }
+            )?,
+            EventKind::SignSent {
+                upstream,
+                fingerprint,
+            } => write!(f, "sign {fingerprint} sent to {upstream}")?,
+            EventKind::SignAnswered {
+                upstream,
+                fingerprint,
+                outcome,
+                elapsed,
+            } => write!(
+                f,
+                "sign {fingerprint} {outcome} from {upstream} after {}",
+                humantime::format_duration(Duration::from_millis(
+                    u64::try_from(elapsed.as_millis()).unwrap_or(u64::MAX)
+                ))
+            )?,
+            EventKind::UpstreamAdded { path } => write!(f, "upstream added {path}")?,
+            EventKind::UpstreamRemoved { path, reason } => {
+                write!(f, "upstream removed {path}: {reason}")?
+            }
+            EventKind::Timeout { upstream } => write!(f, "request to {upstream} timed out")?,
+            EventKind::Missed { count } => write!(f, "fell behind, missed {count} events")?,
+        }
+    }
+}
@@ -1,8 +1,10 @@
 use crate::error::ErrorExt;
-use eyre::Error;
+use eyre::{bail, eyre, Error, WrapErr as _};
 use futures::stream::Stream;
+use nix::sys::stat::Mode;
 use std::{
-    path::Path,
+    os::unix::fs::{FileTypeExt as _, MetadataExt as _},
+    path::{Path, PathBuf},
     pin::Pin,
     task::{Context, Poll},
 };
@@ -18,6 +20,97 @@ pub(crate) struct Incoming<'a> {
     inner: &'a tokio::net::UnixListener,
 }
 
+/// The process on the other end of an accepted connection, from `SO_PEERCRED`
+#[derive(Debug, Clone)]
+pub(crate) struct Peer {
+    pub(crate) uid: u32,
+    pub(crate) pid: Option<i32>,
+    /// Only readable for processes owned by the same user (or if we're root)
+    pub(crate) exe: Option<PathBuf>,
+    /// The arguments joined by spaces, for showing to the user (e.g. to see which host `ssh` is
+    /// connecting to)
+    pub(crate) cmdline: Option<String>,
+}
+
+impl Peer {
+    #[culpa::throws]
+    pub(crate) fn of(stream: &UnixStream) -> Self {
+        let cred = stream.peer_cred()?;
+        let exe = cred
+            .pid()
+            .and_then(|pid| std::fs::read_link(format!("/proc/{pid}/exe")).ok());
+        let cmdline = cred.pid().and_then(|pid| {
+            let cmdline = std::fs::read(format!("/proc/{pid}/cmdline")).ok()?;
+            Some(
+                cmdline
+                    .split(|&b| b == 0)
+                    .filter(|arg| !arg.is_empty())
+                    .map(String::from_utf8_lossy)
+                    .collect::<Vec<_>>()
+                    .join(" "),
+            )
+        });
+        Self {
+            uid: cred.uid(),
+            pid: cred.pid(),
+            exe,
+            cmdline,
+        }
+    }
+}
+
+/// Identifies a specific socket, so we can tell if a path has been replaced by a different socket
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct SocketId {
+    dev: u64,
+    ino: u64,
+}
+
+/// Returned when connecting to an upstream whose path now refers to a different socket
+#[derive(Debug)]
+pub(crate) struct SocketReplaced;
+
+impl std::fmt::Display for SocketReplaced {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("upstream socket has been replaced by a different socket")
+    }
+}
+
+impl std::error::Error for SocketReplaced {}
+
+/// Checks that `path` is a socket owned by us, in a directory that only we can modify, so that
+/// another user can't have it registered as an upstream to receive our sign requests
+#[culpa::throws]
+pub(crate) fn verify_socket(path: &Path) -> SocketId {
+    let uid = nix::unistd::geteuid().as_raw();
+
+    let path = std::fs::canonicalize(path)
+        .with_context(|| format!("failed to resolve {:?}", path.display()))?;
+    let metadata = std::fs::metadata(&path)?;
+    if !metadata.file_type().is_socket() {
+        bail!("{:?} is not a socket", path.display());
+    }
+    if metadata.uid() != uid {
+        bail!("{:?} is owned by uid {}", path.display(), metadata.uid());
+    }
+
+    let dir = path
+        .parent()
+        .ok_or_else(|| eyre!("{:?} has no parent directory", path.display()))?;
+    let dir_metadata = std::fs::metadata(dir)?;
+    if dir_metadata.uid() != uid {
+        bail!("{:?} is owned by uid {}", dir.display(), dir_metadata.uid());
+    }
+    if dir_metadata.mode() & 0o022 != 0 {
+        bail!("{:?} is writable by other users", dir.display());
+    }
+
+    SocketId {
+        dev: metadata.dev(),
+        ino: metadata.ino(),
+    }
+}
+
 impl UnixListener {
     #[culpa::throws]
     pub(crate) fn bind(path: impl AsRef<Path>) -> Self {
@@ -27,6 +120,19 @@ impl UnixListener {
         }
     }
 
+    /// Binds a socket only we can connect to, it's created with those permissions so there's no
+    /// window where other users could connect before it's restricted
+    #[culpa::throws]
+    pub(crate) fn bind_private(path: impl AsRef<Path>) -> Self {
+        let umask = nix::sys::stat::umask(Mode::from_bits_truncate(0o177));
+        let listener = tokio::net::UnixListener::bind(path);
+        nix::sys::stat::umask(umask);
+        Self {
+            inner: listener?,
+            unlink: true,
+        }
+    }
+
     #[culpa::throws]
     pub(crate) fn from_std(listener: std::os::unix::net::UnixListener, unlink: bool) -> Self {
         Self {
@@ -35,7 +141,7 @@ impl UnixListener {
         }
     }
 
-    pub(crate) fn incoming(&self) -> Incoming {
+    pub(crate) fn incoming(&self) -> Incoming<'_> {
         Incoming { inner: &self.inner }
     }
 
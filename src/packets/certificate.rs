@@ -0,0 +1,145 @@
+use bytes::Bytes;
+use ssh_key::{certificate::CertType, public::KeyData};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use super::{fingerprint, PublicKey};
+
+/// The parts of an OpenSSH `*-cert-v01@openssh.com` certificate that matter for choosing and
+/// showing identities, the CA's signature isn't checked as the server does that
+#[derive(Debug, Clone)]
+pub(crate) struct Certificate {
+    /// Whether it's a user or host certificate
+    pub(crate) host: bool,
+    pub(crate) key_id: String,
+    /// Empty if valid for any principal
+    pub(crate) principals: Vec<String>,
+    /// Seconds since the unix epoch
+    pub(crate) valid_after: u64,
+    /// Seconds since the unix epoch, `u64::MAX` if it never expires
+    pub(crate) valid_before: u64,
+    /// The blob of the key being certified
+    pub(crate) key: Bytes,
+    /// The `SHA256:...` fingerprint of the CA key that signed it
+    pub(crate) ca_fingerprint: String,
+}
+
+/// The last second RFC 3339 timestamps can show, at the end of the year 9999
+const LAST_TIMESTAMP: u64 = 253_402_300_799;
+
+/// When a certificate is valid relative to now
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Validity {
+    NotYetValid,
+    Valid,
+    Expired,
+}
+
+fn key_blob(key: &KeyData) -> Option<Bytes> {
+    ssh_key::PublicKey::from(key.clone())
+        .to_bytes()
+        .ok()
+        .map(Bytes::from)
+}
+
+impl Certificate {
+    /// Returns `None` if the blob isn't a certificate
+    pub(crate) fn parse(blob: &[u8]) -> Option<Self> {
+        let certificate = ssh_key::Certificate::from_bytes(blob).ok()?;
+        Some(Self {
+            host: certificate.cert_type() == CertType::Host,
+            key_id: certificate.key_id().to_owned(),
+            principals: certificate.valid_principals().to_vec(),
+            valid_after: certificate.valid_after(),
+            valid_before: certificate.valid_before(),
+            key: key_blob(certificate.public_key())?,
+            ca_fingerprint: fingerprint(&key_blob(certificate.signature_key())?),
+        })
+    }
+
+    pub(crate) fn validity(&self, now: SystemTime) -> Validity {
+        let now = now.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        if now < self.valid_after {
+            Validity::NotYetValid
+        } else if now >= self.valid_before {
+            Validity::Expired
+        } else {
+            Validity::Valid
+        }
+    }
+
+    /// When it becomes valid, formatted for showing to the user
+    pub(crate) fn valid_from(&self) -> String {
+        format_time(self.valid_after)
+    }
+
+    /// When it expires, formatted for showing to the user, `None` if it never expires
+    pub(crate) fn valid_to(&self) -> Option<String> {
+        (self.valid_before != u64::MAX).then(|| format_time(self.valid_before))
+    }
+}
+
+/// Formats seconds since the unix epoch as a timestamp, or as the raw seconds if they're too far
+/// in the future for one
+fn format_time(seconds: u64) -> String {
+    match UNIX_EPOCH.checked_add(Duration::from_secs(seconds)) {
+        Some(time) if seconds <= LAST_TIMESTAMP => {
+            humantime::format_rfc3339_seconds(time).to_string()
+        }
+        _ => format!("{seconds} seconds after the unix epoch"),
+    }
+}
+
+/// Moves certificates to just before the plain key they certify, so that clients try them first,
+/// otherwise keeps the order
+pub(crate) fn certificates_first(keys: Vec<PublicKey>) -> Vec<PublicKey> {
+    let certified: Vec<Option<Bytes>> = keys
+        .iter()
+        .map(|key| Certificate::parse(&key.blob).map(|certificate| certificate.key))
+        .collect();
+    let mut moved = vec![false; keys.len()];
+    let mut ordered = Vec::with_capacity(keys.len());
+    for (i, key) in keys.iter().enumerate() {
+        if moved[i] {
+            continue;
+        }
+        for (j, certified) in certified.iter().enumerate().skip(i + 1) {
+            if certified.as_ref() == Some(&key.blob) && !moved[j] {
+                moved[j] = true;
+                ordered.push(keys[j].clone());
+            }
+        }
+        ordered.push(key.clone());
+    }
+    ordered
+}
+
+impl std::fmt::Display for Validity {
+    #[culpa::throws(std::fmt::Error)]
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) {
+        match self {
+            Self::NotYetValid => write!(f, "not yet valid")?,
+            Self::Valid => write!(f, "valid")?,
+            Self::Expired => write!(f, "expired")?,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{format_time, LAST_TIMESTAMP};
+
+    #[test]
+    fn formats_last_timestamp() {
+        assert_eq!(format_time(LAST_TIMESTAMP), "9999-12-31T23:59:59Z");
+    }
+
+    #[test]
+    fn formats_raw_seconds_past_last_timestamp() {
+        for seconds in [LAST_TIMESTAMP + 1, u64::MAX] {
+            assert_eq!(
+                format_time(seconds),
+                format!("{seconds} seconds after the unix epoch")
+            );
+        }
+    }
+}
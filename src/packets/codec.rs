@@ -1,4 +1,4 @@
-use bytes::BytesMut;
+use bytes::{Bytes, BytesMut};
 use eyre::{bail, Context, Error};
 use tokio_util::codec::{Decoder, Encoder};
 
@@ -6,12 +6,21 @@ use super::{
     util::{BytesExt, BytesMutExt},
     Encode, Parse,
 };
+use crate::capture::Tap;
+
+/// A message that is passed through without being parsed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Frame {
+    pub(crate) kind: u8,
+    pub(crate) contents: Bytes,
+}
 
 #[derive(Debug)]
 pub(crate) struct Codec<O: Parse, I: Encode> {
     length: Option<usize>,
     kind: Option<u8>,
     errored: bool,
+    tap: Option<Tap>,
     _output: std::marker::PhantomData<fn() -> O>,
     _input: std::marker::PhantomData<fn(I)>,
 }
@@ -22,6 +31,7 @@ impl<O: Parse, I: Encode> Default for Codec<O, I> {
             length: Default::default(),
             kind: Default::default(),
             errored: Default::default(),
+            tap: Default::default(),
             _output: Default::default(),
             _input: Default::default(),
         }
@@ -32,6 +42,11 @@ impl<O: Parse, I: Encode> Codec<O, I> {
     pub(crate) fn new() -> Self {
         Self::default()
     }
+
+    /// Records every message decoded or encoded to a capture
+    pub(crate) fn tap(self, tap: Option<Tap>) -> Self {
+        Self { tap, ..self }
+    }
 }
 
 impl<O: Parse, I: Encode> Decoder for Codec<O, I> {
@@ -81,8 +96,21 @@ impl<O: Parse, I: Encode> Decoder for Codec<O, I> {
         self.length = None;
         self.kind = None;
 
+        if let Some(tap) = &self.tap {
+            tap.received(kind, &contents);
+        }
+
         Some(O::parse(kind, contents)?)
     }
+
+    #[culpa::throws]
+    fn decode_eof(&mut self, src: &mut BytesMut) -> Option<Self::Item> {
+        let message = self.decode(src)?;
+        if message.is_none() && (self.length.is_some() || !src.is_empty()) {
+            bail!("stream ended part way through a message");
+        }
+        message
+    }
 }
 
 impl<O: Parse, I: Encode> Encoder<I> for Codec<O, I> {
@@ -99,6 +127,9 @@ impl<O: Parse, I: Encode> Encoder<I> for Codec<O, I> {
         // ensure the message can't write over the length
         let mut msg_buffer = length_buffer.split_off(length_buffer.len());
         msg.encode_to(&mut msg_buffer)?;
+        if let (Some(tap), Some((&kind, contents))) = (&self.tap, msg_buffer.split_first()) {
+            tap.sent(kind, contents);
+        }
         length_buffer.clear();
         let length = u32::try_from(msg_buffer.len()).context("length did not fit in u32")?;
         length_buffer.try_put_u32_be(length)?;
@@ -106,3 +137,22 @@ impl<O: Parse, I: Encode> Encoder<I> for Codec<O, I> {
         dst.unsplit(length_buffer);
     }
 }
+
+impl Parse for Frame {
+    #[culpa::throws]
+    fn parse(kind: u8, contents: Bytes) -> Self {
+        Self { kind, contents }
+    }
+}
+
+impl Encode for Frame {
+    #[culpa::throws]
+    fn encode_to(self, dst: &mut BytesMut) {
+        dst.try_put_u8(self.kind)?;
+        dst.try_put(self.contents)?;
+    }
+
+    fn encoded_length_estimate(&self) -> usize {
+        1 + self.contents.len()
+    }
+}
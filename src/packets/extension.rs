@@ -1,12 +1,21 @@
 use bytes::{Bytes, BytesMut};
 use eyre::{bail, eyre, Error};
+use std::{
+    rc::Rc,
+    time::{Duration, SystemTime},
+};
 
 use super::{
     util::{BytesExt, BytesMutExt},
-    Encode,
+    Encode, PublicKey,
 };
 
-use crate::upstreams::Upstream;
+use crate::{
+    config::TimeoutOverrides,
+    metrics::{Family, Kind, Sample},
+    monitor::{Event, EventKind},
+    upstreams::Upstream,
+};
 
 #[derive(Debug)]
 pub(crate) struct ErrorMsg {
@@ -74,13 +83,390 @@ impl TryFrom<&mut Bytes> for UpstreamListV2 {
                     let forward_adds = bytes
                         .try_get_bool()
                         .ok_or_else(|| eyre!("missing upstream forward_adds {i}"))??;
-                    Ok(Upstream { path, forward_adds })
+                    Ok(Upstream {
+                        path,
+                        forward_adds,
+                        timeouts: TimeoutOverrides::default(),
+                    })
                 })
                 .collect::<Result<_, Error>>()?,
         }
     }
 }
 
+#[derive(Debug)]
+pub(crate) struct Stats {
+    pub(crate) families: Vec<Family>,
+}
+
+impl TryFrom<&mut Bytes> for Stats {
+    type Error = Error;
+
+    #[culpa::throws]
+    fn try_from(bytes: &mut Bytes) -> Self {
+        let length = usize::try_from(bytes.try_get_u32_be().ok_or(eyre!("missing length"))?)?;
+        let mut families = Vec::new();
+        for i in 0..length {
+            let name = bytes
+                .try_get_utf8_string()
+                .ok_or_else(|| eyre!("missing family name {i}"))??;
+            let help = bytes
+                .try_get_utf8_string()
+                .ok_or_else(|| eyre!("missing family help {i}"))??;
+            let kind = match bytes.try_get_u8() {
+                Some(0) => Kind::Counter,
+                Some(1) => Kind::Gauge,
+                Some(2) => Kind::Histogram,
+                Some(kind) => bail!("unknown metric kind {kind}"),
+                None => bail!("missing family kind {i}"),
+            };
+            let samples = usize::try_from(
+                bytes
+                    .try_get_u32_be()
+                    .ok_or_else(|| eyre!("missing sample count {i}"))?,
+            )?;
+            let samples = (0..samples)
+                .map(|_| {
+                    let suffix = bytes
+                        .try_get_utf8_string()
+                        .ok_or_else(|| eyre!("missing sample suffix"))??;
+                    let labels = usize::try_from(
+                        bytes
+                            .try_get_u32_be()
+                            .ok_or_else(|| eyre!("missing label count"))?,
+                    )?;
+                    let labels = (0..labels)
+                        .map(|_| {
+                            let name = bytes
+                                .try_get_utf8_string()
+                                .ok_or_else(|| eyre!("missing label name"))??;
+                            let value = bytes
+                                .try_get_utf8_string()
+                                .ok_or_else(|| eyre!("missing label value"))??;
+                            Ok((name, value))
+                        })
+                        .collect::<Result<_, Error>>()?;
+                    let value = f64::from_bits(
+                        bytes
+                            .try_get_u64_be()
+                            .ok_or_else(|| eyre!("missing sample value"))?,
+                    );
+                    Ok(Sample {
+                        suffix,
+                        labels,
+                        value,
+                    })
+                })
+                .collect::<Result<_, Error>>()?;
+            families.push(Family {
+                name,
+                help,
+                kind,
+                samples,
+            });
+        }
+        Stats { families }
+    }
+}
+
+/// A summary of the daemon's health, durations are how long ago something happened
+#[derive(Debug)]
+pub(crate) struct DaemonStatus {
+    pub(crate) version: String,
+    pub(crate) uptime: Duration,
+    pub(crate) path: Option<String>,
+    pub(crate) connections: u32,
+    /// Whether every upstream was locked through the daemon
+    pub(crate) locked: bool,
+    pub(crate) upstreams: Vec<UpstreamStatus>,
+}
+
+#[derive(Debug)]
+pub(crate) struct UpstreamStatus {
+    pub(crate) path: Rc<str>,
+    pub(crate) forward_adds: bool,
+    pub(crate) healthy: bool,
+    pub(crate) last_success: Option<Duration>,
+    pub(crate) last_failure: Option<(Duration, String)>,
+    /// The number of cached identities and when they were fetched, if they have been
+    pub(crate) cached: Option<(u32, Duration)>,
+}
+
+impl TryFrom<&mut Bytes> for DaemonStatus {
+    type Error = Error;
+
+    #[culpa::throws]
+    fn try_from(bytes: &mut Bytes) -> Self {
+        let version = bytes
+            .try_get_utf8_string()
+            .ok_or_else(|| eyre!("missing version"))??;
+        let uptime = bytes
+            .try_get_duration()
+            .ok_or_else(|| eyre!("missing uptime"))?;
+        let path = bytes
+            .try_get_utf8_string()
+            .ok_or_else(|| eyre!("missing path"))??;
+        let connections = bytes
+            .try_get_u32_be()
+            .ok_or_else(|| eyre!("missing connections"))?;
+        let locked = bytes
+            .try_get_bool()
+            .ok_or_else(|| eyre!("missing locked"))??;
+        let length = usize::try_from(
+            bytes
+                .try_get_u32_be()
+                .ok_or_else(|| eyre!("missing upstreams length"))?,
+        )?;
+        let upstreams = (0..length)
+            .map(|i| {
+                let missing = |field| eyre!("missing upstream {field} {i}");
+                let path = bytes
+                    .try_get_utf8_string_rc()
+                    .ok_or_else(|| missing("path"))??;
+                let forward_adds = bytes
+                    .try_get_bool()
+                    .ok_or_else(|| missing("forward_adds"))??;
+                let healthy = bytes.try_get_bool().ok_or_else(|| missing("healthy"))??;
+                let last_success =
+                    match bytes.try_get_bool().ok_or_else(|| missing("success"))?? {
+                        true => Some(bytes.try_get_duration().ok_or_else(|| missing("success"))?),
+                        false => None,
+                    };
+                let last_failure =
+                    match bytes.try_get_bool().ok_or_else(|| missing("failure"))?? {
+                        true => Some((
+                            bytes.try_get_duration().ok_or_else(|| missing("failure"))?,
+                            bytes
+                                .try_get_utf8_string()
+                                .ok_or_else(|| missing("failure"))??,
+                        )),
+                        false => None,
+                    };
+                let cached = match bytes.try_get_bool().ok_or_else(|| missing("cache"))?? {
+                    true => Some((
+                        bytes.try_get_u32_be().ok_or_else(|| missing("cache"))?,
+                        bytes.try_get_duration().ok_or_else(|| missing("cache"))?,
+                    )),
+                    false => None,
+                };
+                Ok(UpstreamStatus {
+                    path,
+                    forward_adds,
+                    healthy,
+                    last_success,
+                    last_failure,
+                    cached,
+                })
+            })
+            .collect::<Result<_, Error>>()?;
+        DaemonStatus {
+            version,
+            uptime,
+            path: Some(path).filter(|path| !path.is_empty()),
+            connections,
+            locked,
+            upstreams,
+        }
+    }
+}
+
+/// What `sshagmux top` shows about each upstream beyond its status
+#[derive(Debug)]
+pub(crate) struct UpstreamDetails {
+    pub(crate) preferred: Option<Rc<str>>,
+    pub(crate) upstreams: Vec<UpstreamDetail>,
+}
+
+#[derive(Debug)]
+pub(crate) struct UpstreamDetail {
+    pub(crate) path: Rc<str>,
+    /// How long the last successful identities request took
+    pub(crate) latency: Option<Duration>,
+    /// The identities cached from that request
+    pub(crate) identities: Option<Vec<PublicKey>>,
+}
+
+impl TryFrom<&mut Bytes> for UpstreamDetails {
+    type Error = Error;
+
+    #[culpa::throws]
+    fn try_from(bytes: &mut Bytes) -> Self {
+        let preferred = bytes
+            .try_get_utf8_string_rc()
+            .ok_or_else(|| eyre!("missing preferred"))??;
+        let length = usize::try_from(
+            bytes
+                .try_get_u32_be()
+                .ok_or_else(|| eyre!("missing upstreams length"))?,
+        )?;
+        let upstreams = (0..length)
+            .map(|i| {
+                let missing = |field| eyre!("missing upstream {field} {i}");
+                let path = bytes
+                    .try_get_utf8_string_rc()
+                    .ok_or_else(|| missing("path"))??;
+                let latency = match bytes.try_get_bool().ok_or_else(|| missing("latency"))?? {
+                    true => Some(bytes.try_get_duration().ok_or_else(|| missing("latency"))?),
+                    false => None,
+                };
+                let identities = match bytes
+                    .try_get_bool()
+                    .ok_or_else(|| missing("identities"))??
+                {
+                    true => {
+                        let length = usize::try_from(
+                            bytes
+                                .try_get_u32_be()
+                                .ok_or_else(|| missing("identities length"))?,
+                        )?;
+                        Some(
+                            (0..length)
+                                .map(|_| {
+                                    let blob = bytes
+                                        .try_get_string()
+                                        .ok_or_else(|| missing("key blob"))?;
+                                    let comment = bytes
+                                        .try_get_string()
+                                        .ok_or_else(|| missing("key comment"))?;
+                                    Ok(PublicKey { blob, comment })
+                                })
+                                .collect::<Result<_, Error>>()?,
+                        )
+                    }
+                    false => None,
+                };
+                Ok(UpstreamDetail {
+                    path,
+                    latency,
+                    identities,
+                })
+            })
+            .collect::<Result<_, Error>>()?;
+        UpstreamDetails {
+            preferred: Some(preferred).filter(|preferred| !preferred.is_empty()),
+            upstreams,
+        }
+    }
+}
+
+impl TryFrom<&mut Bytes> for Event {
+    type Error = Error;
+
+    #[culpa::throws]
+    fn try_from(bytes: &mut Bytes) -> Self {
+        let missing = |field| eyre!("missing event {field}");
+        let tag = bytes.try_get_u8().ok_or_else(|| missing("kind"))?;
+        let time =
+            SystemTime::UNIX_EPOCH + bytes.try_get_duration().ok_or_else(|| missing("time"))?;
+        let kind = match tag {
+            0 => {
+                let connection = bytes
+                    .try_get_u64_be()
+                    .ok_or_else(|| missing("connection"))?;
+                let uid = bytes.try_get_u32_be().ok_or_else(|| missing("uid"))?;
+                let pid = match bytes.try_get_bool().ok_or_else(|| missing("pid"))?? {
+                    true => Some(i32::try_from(
+                        bytes.try_get_u32_be().ok_or_else(|| missing("pid"))?,
+                    )?),
+                    false => None,
+                };
+                let exe = match bytes.try_get_bool().ok_or_else(|| missing("exe"))?? {
+                    true => Some(
+                        bytes
+                            .try_get_utf8_string()
+                            .ok_or_else(|| missing("exe"))??,
+                    ),
+                    false => None,
+                };
+                EventKind::ConnectionOpened {
+                    connection,
+                    uid,
+                    pid,
+                    exe,
+                }
+            }
+            1 => EventKind::ConnectionClosed {
+                connection: bytes
+                    .try_get_u64_be()
+                    .ok_or_else(|| missing("connection"))?,
+            },
+            2 => {
+                let connection = bytes
+                    .try_get_u64_be()
+                    .ok_or_else(|| missing("connection"))?;
+                let kind = bytes
+                    .try_get_utf8_string()
+                    .ok_or_else(|| missing("request kind"))??;
+                let fingerprint = match bytes
+                    .try_get_bool()
+                    .ok_or_else(|| missing("fingerprint"))??
+                {
+                    true => Some(
+                        bytes
+                            .try_get_utf8_string()
+                            .ok_or_else(|| missing("fingerprint"))??,
+                    ),
+                    false => None,
+                };
+                EventKind::Request {
+                    connection,
+                    kind,
+                    fingerprint,
+                }
+            }
+            3 => EventKind::SignSent {
+                upstream: bytes
+                    .try_get_utf8_string_rc()
+                    .ok_or_else(|| missing("upstream"))??,
+                fingerprint: bytes
+                    .try_get_utf8_string()
+                    .ok_or_else(|| missing("fingerprint"))??,
+            },
+            4 => EventKind::SignAnswered {
+                upstream: bytes
+                    .try_get_utf8_string_rc()
+                    .ok_or_else(|| missing("upstream"))??,
+                fingerprint: bytes
+                    .try_get_utf8_string()
+                    .ok_or_else(|| missing("fingerprint"))??,
+                outcome: bytes
+                    .try_get_utf8_string()
+                    .ok_or_else(|| missing("outcome"))??,
+                elapsed: bytes.try_get_duration().ok_or_else(|| missing("elapsed"))?,
+            },
+            5 => EventKind::UpstreamAdded {
+                path: bytes
+                    .try_get_utf8_string_rc()
+                    .ok_or_else(|| missing("path"))??,
+            },
+            6 => EventKind::UpstreamRemoved {
+                path: bytes
+                    .try_get_utf8_string_rc()
+                    .ok_or_else(|| missing("path"))??,
+                reason: bytes
+                    .try_get_utf8_string()
+                    .ok_or_else(|| missing("reason"))??,
+            },
+            7 => EventKind::Timeout {
+                upstream: bytes
+                    .try_get_utf8_string_rc()
+                    .ok_or_else(|| missing("upstream"))??,
+            },
+            8 => EventKind::Missed {
+                count: bytes.try_get_u64_be().ok_or_else(|| missing("count"))?,
+            },
+            9 => EventKind::Responded {
+                connection: bytes
+                    .try_get_u64_be()
+                    .ok_or_else(|| missing("connection"))?,
+                success: bytes.try_get_bool().ok_or_else(|| missing("success"))??,
+            },
+            tag => bail!("unknown event kind {tag}"),
+        };
+        Event { time, kind }
+    }
+}
+
 #[derive(Debug)]
 pub(crate) struct NoResponse;
 
@@ -93,17 +479,53 @@ impl TryFrom<&mut Bytes> for NoResponse {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub(crate) enum Extension {
     AddUpstreamV2(Upstream),
+    /// Same as v2, with added timeout overrides
+    AddUpstreamV3(Upstream),
     ListUpstreamsV2,
-    Unknown { kind: String, contents: Bytes },
+    Stats,
+    Status,
+    UpstreamDetails,
+    RemoveUpstream(Rc<str>),
+    /// Try this upstream first, until the daemon's config is next reloaded
+    SetPreferred(Rc<str>),
+    /// Subscribes to a live stream of events, each sent as a separate response after the initial
+    /// success until the client disconnects
+    Monitor,
+    /// Sent by `ssh` after key exchange to tell the agent which host the connection is to
+    SessionBind(SessionBind),
+    /// Succeeds only if this is the daemon with this instance id, to spot upstreams that lead back
+    /// to the daemon itself, e.g. through a forwarded agent
+    LoopCheck(u64),
+    Unknown {
+        kind: String,
+        contents: Bytes,
+    },
+}
+
+/// The contents of a `session-bind@openssh.com` extension
+#[derive(Debug, Clone)]
+pub(crate) struct SessionBind {
+    /// The public key blob of the host that was connected to
+    pub(crate) host_key: Bytes,
+    pub(crate) session_id: Bytes,
+    /// The host's signature over `session_id`
+    pub(crate) signature: Bytes,
+    /// Whether the agent is being forwarded over this connection, rather than used to
+    /// authenticate it
+    pub(crate) forwarding: bool,
 }
 
 #[derive(Debug)]
 pub(crate) enum ExtensionResponse {
     Error(ErrorMsg),
     UpstreamListV2(UpstreamListV2),
+    Stats(Stats),
+    Status(DaemonStatus),
+    UpstreamDetails(UpstreamDetails),
+    Event(Event),
 }
 
 impl Extension {
@@ -117,9 +539,77 @@ impl Extension {
                 let forward_adds = contents
                     .try_get_bool()
                     .ok_or_else(|| eyre!("missing forward_adds"))??;
-                Self::AddUpstreamV2(Upstream { path, forward_adds })
+                Self::AddUpstreamV2(Upstream {
+                    path,
+                    forward_adds,
+                    timeouts: TimeoutOverrides::default(),
+                })
+            }
+            "add-upstream-v3@nemo157.com" => {
+                let path = contents
+                    .try_get_utf8_string_rc()
+                    .ok_or_else(|| eyre!("missing path"))??;
+                let forward_adds = contents
+                    .try_get_bool()
+                    .ok_or_else(|| eyre!("missing forward_adds"))??;
+                let mut timeout = |name| {
+                    contents
+                        .try_get_duration()
+                        .map(|timeout| (!timeout.is_zero()).then_some(timeout))
+                        .ok_or_else(|| eyre!("missing {name} timeout"))
+                };
+                let timeouts = TimeoutOverrides {
+                    identities: timeout("identities")?,
+                    sign: timeout("sign")?,
+                    sign_sk: timeout("sign-sk")?,
+                    management: timeout("management")?,
+                };
+                Self::AddUpstreamV3(Upstream {
+                    path,
+                    forward_adds,
+                    timeouts,
+                })
             }
             "list-upstreams-v2@nemo157.com" => Self::ListUpstreamsV2,
+            "stats@nemo157.com" => Self::Stats,
+            "status@nemo157.com" => Self::Status,
+            "upstream-details@nemo157.com" => Self::UpstreamDetails,
+            "remove-upstream@nemo157.com" => Self::RemoveUpstream(
+                contents
+                    .try_get_utf8_string_rc()
+                    .ok_or_else(|| eyre!("missing path"))??,
+            ),
+            "set-preferred@nemo157.com" => Self::SetPreferred(
+                contents
+                    .try_get_utf8_string_rc()
+                    .ok_or_else(|| eyre!("missing path"))??,
+            ),
+            "monitor@nemo157.com" => Self::Monitor,
+            "loop-check@nemo157.com" => Self::LoopCheck(
+                contents
+                    .try_get_u64_be()
+                    .ok_or_else(|| eyre!("missing instance"))?,
+            ),
+            "session-bind@openssh.com" => {
+                let host_key = contents
+                    .try_get_string()
+                    .ok_or_else(|| eyre!("missing host key"))?;
+                let session_id = contents
+                    .try_get_string()
+                    .ok_or_else(|| eyre!("missing session identifier"))?;
+                let signature = contents
+                    .try_get_string()
+                    .ok_or_else(|| eyre!("missing signature"))?;
+                let forwarding = contents
+                    .try_get_bool()
+                    .ok_or_else(|| eyre!("missing forwarding flag"))??;
+                Self::SessionBind(SessionBind {
+                    host_key,
+                    session_id,
+                    signature,
+                    forwarding,
+                })
+            }
             _ => {
                 let contents = contents.split_to(contents.len());
                 Self::Unknown { kind, contents }
@@ -131,10 +621,36 @@ impl Extension {
         extension
     }
 
+    /// Our own extensions for administering the daemon, these can redirect sign requests so may
+    /// only be served on the control socket
+    pub(crate) fn is_management(&self) -> bool {
+        match self {
+            Self::AddUpstreamV2(_)
+            | Self::AddUpstreamV3(_)
+            | Self::ListUpstreamsV2
+            | Self::Stats
+            | Self::Status
+            | Self::UpstreamDetails
+            | Self::RemoveUpstream(_)
+            | Self::SetPreferred(_)
+            | Self::Monitor => true,
+            Self::SessionBind(_) | Self::LoopCheck(_) | Self::Unknown { .. } => false,
+        }
+    }
+
     pub(crate) fn kind(&self) -> &str {
         match self {
             Self::AddUpstreamV2 { .. } => "add-upstream-v2@nemo157.com",
+            Self::AddUpstreamV3 { .. } => "add-upstream-v3@nemo157.com",
             Self::ListUpstreamsV2 => "list-upstreams-v2@nemo157.com",
+            Self::Stats => "stats@nemo157.com",
+            Self::Status => "status@nemo157.com",
+            Self::UpstreamDetails => "upstream-details@nemo157.com",
+            Self::RemoveUpstream(_) => "remove-upstream@nemo157.com",
+            Self::SetPreferred(_) => "set-preferred@nemo157.com",
+            Self::Monitor => "monitor@nemo157.com",
+            Self::SessionBind(_) => "session-bind@openssh.com",
+            Self::LoopCheck(_) => "loop-check@nemo157.com",
             Self::Unknown { kind, .. } => kind,
         }
     }
@@ -144,7 +660,11 @@ impl ExtensionResponse {
     pub(crate) fn kind(&self) -> u8 {
         match self {
             Self::Error(..) => super::response::SSH_AGENT_EXTENSION_FAILURE,
-            Self::UpstreamListV2(..) => super::response::SSH_AGENT_SUCCESS,
+            Self::UpstreamListV2(..)
+            | Self::Stats(..)
+            | Self::Status(..)
+            | Self::UpstreamDetails(..)
+            | Self::Event(..) => super::response::SSH_AGENT_SUCCESS,
         }
     }
 }
@@ -158,7 +678,38 @@ impl Encode for Extension {
                 dst.try_put_string(upstream.path.as_bytes())?;
                 dst.try_put_bool(upstream.forward_adds)?;
             }
-            Self::ListUpstreamsV2 => {}
+            Self::AddUpstreamV3(upstream) => {
+                dst.try_put_string(upstream.path.as_bytes())?;
+                dst.try_put_bool(upstream.forward_adds)?;
+                let TimeoutOverrides {
+                    identities,
+                    sign,
+                    sign_sk,
+                    management,
+                } = upstream.timeouts;
+                for timeout in [identities, sign, sign_sk, management] {
+                    // 0 means use the daemon's configured timeout, overrides are validated to be
+                    // at least a millisecond so can't be confused with it
+                    dst.try_put_duration(timeout.unwrap_or_default())?;
+                }
+            }
+            Self::ListUpstreamsV2
+            | Self::Stats
+            | Self::Status
+            | Self::UpstreamDetails
+            | Self::Monitor => {}
+            Self::RemoveUpstream(path) | Self::SetPreferred(path) => {
+                dst.try_put_string(path.as_bytes())?;
+            }
+            Self::SessionBind(bind) => {
+                dst.try_put_string(bind.host_key)?;
+                dst.try_put_string(bind.session_id)?;
+                dst.try_put_string(bind.signature)?;
+                dst.try_put_bool(bind.forwarding)?;
+            }
+            Self::LoopCheck(instance) => {
+                dst.try_put_u64_be(instance)?;
+            }
             Self::Unknown { contents, .. } => {
                 dst.try_put(contents)?;
             }
@@ -169,7 +720,22 @@ impl Encode for Extension {
         4 + self.kind().len()
             + match self {
                 Self::AddUpstreamV2(upstream) => 4 + upstream.path.len() + 1,
-                Self::ListUpstreamsV2 => 0,
+                Self::AddUpstreamV3(upstream) => 4 + upstream.path.len() + 1 + 4 * 8,
+                Self::ListUpstreamsV2
+                | Self::Stats
+                | Self::Status
+                | Self::UpstreamDetails
+                | Self::Monitor => 0,
+                Self::RemoveUpstream(path) | Self::SetPreferred(path) => 4 + path.len(),
+                Self::SessionBind(bind) => {
+                    4 + bind.host_key.len()
+                        + 4
+                        + bind.session_id.len()
+                        + 4
+                        + bind.signature.len()
+                        + 1
+                }
+                Self::LoopCheck(_) => 8,
                 Self::Unknown { contents, .. } => contents.len(),
             }
     }
@@ -192,6 +758,161 @@ impl Encode for ExtensionResponse {
                     dst.try_put_bool(upstream.forward_adds)?;
                 }
             }
+            Self::Stats(Stats { families }) => {
+                dst.try_put_u32_be(u32::try_from(families.len())?)?;
+                for family in families {
+                    dst.try_put_string(family.name.as_bytes())?;
+                    dst.try_put_string(family.help.as_bytes())?;
+                    dst.try_put_u8(match family.kind {
+                        Kind::Counter => 0,
+                        Kind::Gauge => 1,
+                        Kind::Histogram => 2,
+                    })?;
+                    dst.try_put_u32_be(u32::try_from(family.samples.len())?)?;
+                    for sample in family.samples {
+                        dst.try_put_string(sample.suffix.as_bytes())?;
+                        dst.try_put_u32_be(u32::try_from(sample.labels.len())?)?;
+                        for (name, value) in sample.labels {
+                            dst.try_put_string(name.as_bytes())?;
+                            dst.try_put_string(value.as_bytes())?;
+                        }
+                        dst.try_put_u64_be(sample.value.to_bits())?;
+                    }
+                }
+            }
+            Self::Status(status) => {
+                dst.try_put_string(status.version.as_bytes())?;
+                dst.try_put_duration(status.uptime)?;
+                dst.try_put_string(status.path.unwrap_or_default().as_bytes())?;
+                dst.try_put_u32_be(status.connections)?;
+                dst.try_put_bool(status.locked)?;
+                dst.try_put_u32_be(u32::try_from(status.upstreams.len())?)?;
+                for upstream in status.upstreams {
+                    dst.try_put_string(upstream.path.as_bytes())?;
+                    dst.try_put_bool(upstream.forward_adds)?;
+                    dst.try_put_bool(upstream.healthy)?;
+                    dst.try_put_bool(upstream.last_success.is_some())?;
+                    if let Some(ago) = upstream.last_success {
+                        dst.try_put_duration(ago)?;
+                    }
+                    dst.try_put_bool(upstream.last_failure.is_some())?;
+                    if let Some((ago, error)) = upstream.last_failure {
+                        dst.try_put_duration(ago)?;
+                        dst.try_put_string(error.as_bytes())?;
+                    }
+                    dst.try_put_bool(upstream.cached.is_some())?;
+                    if let Some((count, ago)) = upstream.cached {
+                        dst.try_put_u32_be(count)?;
+                        dst.try_put_duration(ago)?;
+                    }
+                }
+            }
+            Self::UpstreamDetails(details) => {
+                dst.try_put_string(details.preferred.as_deref().unwrap_or_default().as_bytes())?;
+                dst.try_put_u32_be(u32::try_from(details.upstreams.len())?)?;
+                for upstream in details.upstreams {
+                    dst.try_put_string(upstream.path.as_bytes())?;
+                    dst.try_put_bool(upstream.latency.is_some())?;
+                    if let Some(latency) = upstream.latency {
+                        dst.try_put_duration(latency)?;
+                    }
+                    dst.try_put_bool(upstream.identities.is_some())?;
+                    if let Some(identities) = upstream.identities {
+                        dst.try_put_u32_be(u32::try_from(identities.len())?)?;
+                        for key in identities {
+                            dst.try_put_string(key.blob)?;
+                            dst.try_put_string(key.comment)?;
+                        }
+                    }
+                }
+            }
+            Self::Event(Event { time, kind }) => {
+                dst.try_put_u8(match kind {
+                    EventKind::ConnectionOpened { .. } => 0,
+                    EventKind::ConnectionClosed { .. } => 1,
+                    EventKind::Request { .. } => 2,
+                    EventKind::SignSent { .. } => 3,
+                    EventKind::SignAnswered { .. } => 4,
+                    EventKind::UpstreamAdded { .. } => 5,
+                    EventKind::UpstreamRemoved { .. } => 6,
+                    EventKind::Timeout { .. } => 7,
+                    EventKind::Missed { .. } => 8,
+                    EventKind::Responded { .. } => 9,
+                })?;
+                dst.try_put_duration(time.duration_since(SystemTime::UNIX_EPOCH)?)?;
+                match kind {
+                    EventKind::ConnectionOpened {
+                        connection,
+                        uid,
+                        pid,
+                        exe,
+                    } => {
+                        dst.try_put_u64_be(connection)?;
+                        dst.try_put_u32_be(uid)?;
+                        dst.try_put_bool(pid.is_some())?;
+                        if let Some(pid) = pid {
+                            dst.try_put_u32_be(u32::try_from(pid)?)?;
+                        }
+                        dst.try_put_bool(exe.is_some())?;
+                        if let Some(exe) = exe {
+                            dst.try_put_string(exe.as_bytes())?;
+                        }
+                    }
+                    EventKind::ConnectionClosed { connection } => {
+                        dst.try_put_u64_be(connection)?;
+                    }
+                    EventKind::Request {
+                        connection,
+                        kind,
+                        fingerprint,
+                    } => {
+                        dst.try_put_u64_be(connection)?;
+                        dst.try_put_string(kind.as_bytes())?;
+                        dst.try_put_bool(fingerprint.is_some())?;
+                        if let Some(fingerprint) = fingerprint {
+                            dst.try_put_string(fingerprint.as_bytes())?;
+                        }
+                    }
+                    EventKind::SignSent {
+                        upstream,
+                        fingerprint,
+                    } => {
+                        dst.try_put_string(upstream.as_bytes())?;
+                        dst.try_put_string(fingerprint.as_bytes())?;
+                    }
+                    EventKind::SignAnswered {
+                        upstream,
+                        fingerprint,
+                        outcome,
+                        elapsed,
+                    } => {
+                        dst.try_put_string(upstream.as_bytes())?;
+                        dst.try_put_string(fingerprint.as_bytes())?;
+                        dst.try_put_string(outcome.as_bytes())?;
+                        dst.try_put_duration(elapsed)?;
+                    }
+                    EventKind::UpstreamAdded { path } => {
+                        dst.try_put_string(path.as_bytes())?;
+                    }
+                    EventKind::UpstreamRemoved { path, reason } => {
+                        dst.try_put_string(path.as_bytes())?;
+                        dst.try_put_string(reason.as_bytes())?;
+                    }
+                    EventKind::Timeout { upstream } => {
+                        dst.try_put_string(upstream.as_bytes())?;
+                    }
+                    EventKind::Missed { count } => {
+                        dst.try_put_u64_be(count)?;
+                    }
+                    EventKind::Responded {
+                        connection,
+                        success,
+                    } => {
+                        dst.try_put_u64_be(connection)?;
+                        dst.try_put_bool(success)?;
+                    }
+                }
+            }
         }
     }
 
@@ -206,6 +927,111 @@ impl Encode for ExtensionResponse {
                     .map(|upstream| 4 + upstream.path.len() + 1)
                     .sum::<usize>()
             }
+            Self::Stats(Stats { families }) => {
+                4 + families
+                    .iter()
+                    .map(|family| {
+                        4 + family.name.len()
+                            + 4
+                            + family.help.len()
+                            + 1
+                            + 4
+                            + family
+                                .samples
+                                .iter()
+                                .map(|sample| {
+                                    4 + sample.suffix.len()
+                                        + 4
+                                        + sample
+                                            .labels
+                                            .iter()
+                                            .map(|(name, value)| 4 + name.len() + 4 + value.len())
+                                            .sum::<usize>()
+                                        + 8
+                                })
+                                .sum::<usize>()
+                    })
+                    .sum::<usize>()
+            }
+            Self::Status(status) => {
+                4 + status.version.len()
+                    + 8
+                    + 4
+                    + status.path.as_ref().map_or(0, |path| path.len())
+                    + 4
+                    + 1
+                    + 4
+                    + status
+                        .upstreams
+                        .iter()
+                        .map(|upstream| {
+                            4 + upstream.path.len()
+                                + 3
+                                + 9
+                                + 1
+                                + upstream
+                                    .last_failure
+                                    .as_ref()
+                                    .map_or(0, |(_, error)| 8 + 4 + error.len())
+                                + 13
+                        })
+                        .sum::<usize>()
+            }
+            Self::UpstreamDetails(details) => {
+                4 + details.preferred.as_ref().map_or(0, |path| path.len())
+                    + 4
+                    + details
+                        .upstreams
+                        .iter()
+                        .map(|upstream| {
+                            4 + upstream.path.len()
+                                + 9
+                                + 1
+                                + 4
+                                + upstream
+                                    .identities
+                                    .iter()
+                                    .flatten()
+                                    .map(|key| 4 + key.blob.len() + 4 + key.comment.len())
+                                    .sum::<usize>()
+                        })
+                        .sum::<usize>()
+            }
+            Self::Event(event) => {
+                1 + 8
+                    + match &event.kind {
+                        EventKind::ConnectionOpened { exe, .. } => {
+                            8 + 4 + 5 + 1 + exe.as_ref().map_or(0, |exe| 4 + exe.len())
+                        }
+                        EventKind::ConnectionClosed { .. } | EventKind::Missed { .. } => 8,
+                        EventKind::Request {
+                            kind, fingerprint, ..
+                        } => {
+                            8 + 4
+                                + kind.len()
+                                + 1
+                                + fingerprint
+                                    .as_ref()
+                                    .map_or(0, |fingerprint| 4 + fingerprint.len())
+                        }
+                        EventKind::SignSent {
+                            upstream,
+                            fingerprint,
+                        } => 4 + upstream.len() + 4 + fingerprint.len(),
+                        EventKind::SignAnswered {
+                            upstream,
+                            fingerprint,
+                            outcome,
+                            ..
+                        } => 4 + upstream.len() + 4 + fingerprint.len() + 4 + outcome.len() + 8,
+                        EventKind::UpstreamAdded { path } => 4 + path.len(),
+                        EventKind::UpstreamRemoved { path, reason } => {
+                            4 + path.len() + 4 + reason.len()
+                        }
+                        EventKind::Timeout { upstream } => 4 + upstream.len(),
+                        EventKind::Responded { .. } => 8 + 1,
+                    }
+            }
         }
     }
 }
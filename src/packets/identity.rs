@@ -0,0 +1,80 @@
+use bytes::Bytes;
+use eyre::{bail, eyre, Error};
+use ssh_key::{private::KeypairData, public::KeyData, Algorithm};
+use std::time::Duration;
+
+use super::util::BytesExt;
+
+const SSH_AGENT_CONSTRAIN_LIFETIME: u8 = 1;
+const SSH_AGENT_CONSTRAIN_CONFIRM: u8 = 2;
+const SSH_AGENT_CONSTRAIN_EXTENSION: u8 = 255;
+
+/// A restriction on how an added identity may be used
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Constraint {
+    /// The identity should be removed after this long
+    Lifetime(Duration),
+    /// The user must confirm every use of the identity
+    Confirm,
+}
+
+/// The decoded contents of an add identity request
+pub(crate) struct Identity {
+    pub(crate) keypair: KeypairData,
+    pub(crate) comment: Bytes,
+    pub(crate) constraints: Vec<Constraint>,
+}
+
+impl Identity {
+    /// Decodes the contents following the key type of an add identity request, constraints are
+    /// only allowed if `constrained` is set
+    #[culpa::throws]
+    pub(crate) fn parse(key_type: &str, contents: &[u8], constrained: bool) -> Self {
+        let algorithm = Algorithm::new(key_type)?;
+        let mut reader = contents;
+        let keypair = KeypairData::decode_as(&mut reader, algorithm)?;
+
+        let mut contents = Bytes::copy_from_slice(reader);
+        let comment = contents
+            .try_get_string()
+            .ok_or_else(|| eyre!("missing comment"))?;
+
+        let mut constraints = Vec::new();
+        while let Some(kind) = contents.try_get_u8() {
+            if !constrained {
+                bail!("constraints are only allowed when adding a constrained identity");
+            }
+            constraints.push(match kind {
+                SSH_AGENT_CONSTRAIN_LIFETIME => {
+                    let seconds = contents
+                        .try_get_u32_be()
+                        .ok_or_else(|| eyre!("missing lifetime"))?;
+                    Constraint::Lifetime(Duration::from_secs(seconds.into()))
+                }
+                SSH_AGENT_CONSTRAIN_CONFIRM => Constraint::Confirm,
+                SSH_AGENT_CONSTRAIN_EXTENSION => {
+                    let name = contents
+                        .try_get_utf8_string()
+                        .ok_or_else(|| eyre!("missing constraint extension name"))??;
+                    // The extension contents aren't length-prefixed, so we can't skip over ones we
+                    // don't understand
+                    bail!("unsupported constraint extension {name:?}");
+                }
+                _ => bail!("unknown constraint {kind}"),
+            });
+        }
+
+        Self {
+            keypair,
+            comment,
+            constraints,
+        }
+    }
+
+    /// The public key blob, as used in identities and sign requests
+    #[culpa::throws]
+    pub(crate) fn public_blob(&self) -> Bytes {
+        let key_data = KeyData::try_from(&self.keypair)?;
+        Bytes::from(ssh_key::PublicKey::from(key_data).to_bytes()?)
+    }
+}
@@ -0,0 +1,150 @@
+use std::{
+    fmt::{self, Debug},
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use super::{fingerprint, Extension, ExtensionResponse, PublicKey, Request, Response};
+
+/// Set once at startup from the logging config, formatting can't reach the config itself
+static REDACT: AtomicBool = AtomicBool::new(false);
+
+/// Hide sign payloads, signatures and key blobs in `Logged` output from now on
+pub(crate) fn redact_logs(redact: bool) {
+    REDACT.store(redact, Ordering::Relaxed);
+}
+
+/// Formats a request or response for the logs, the same as `Debug` unless redaction is enabled,
+/// then payloads are replaced by their length and key blobs by their fingerprint
+pub(crate) struct Logged<'a, T>(pub(crate) &'a T);
+
+fn redacting() -> bool {
+    REDACT.load(Ordering::Relaxed)
+}
+
+fn length(bytes: &[u8]) -> impl Debug + use<'_> {
+    fmt::from_fn(move |f| write!(f, "<{} bytes>", bytes.len()))
+}
+
+fn key(blob: &[u8]) -> impl Debug {
+    let fingerprint = fingerprint(blob);
+    fmt::from_fn(move |f| f.write_str(&fingerprint))
+}
+
+impl Debug for Logged<'_, PublicKey> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if !redacting() {
+            return self.0.fmt(f);
+        }
+        f.debug_struct("PublicKey")
+            .field("key", &key(&self.0.blob))
+            .field("comment", &self.0.comment())
+            .finish()
+    }
+}
+
+impl Debug for Logged<'_, Request> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Smartcard PINs are always hidden, we don't parse those requests so can't do it in their
+        // `Debug` impl like for passphrases
+        if let Request::Unknown { kind, contents } = self.0 {
+            if Request::carries_secrets(*kind) {
+                return f
+                    .debug_struct("Unknown")
+                    .field("kind", kind)
+                    .field("contents", &length(contents))
+                    .finish();
+            }
+        }
+        if !redacting() {
+            return self.0.fmt(f);
+        }
+        match self.0 {
+            Request::SignRequest { blob, data, flags } => f
+                .debug_struct("SignRequest")
+                .field("key", &key(blob))
+                .field("data", &length(data))
+                .field("flags", flags)
+                .finish(),
+            Request::RemoveIdentity { blob } => f
+                .debug_struct("RemoveIdentity")
+                .field("key", &key(blob))
+                .finish(),
+            Request::Extension(Extension::SessionBind(bind)) => f
+                .debug_tuple("Extension")
+                .field(&fmt::from_fn(|f| {
+                    f.debug_struct("SessionBind")
+                        .field("host_key", &key(&bind.host_key))
+                        .field("session_id", &length(&bind.session_id))
+                        .field("signature", &length(&bind.signature))
+                        .field("forwarding", &bind.forwarding)
+                        .finish()
+                }))
+                .finish(),
+            Request::Extension(Extension::Unknown { kind, contents }) => f
+                .debug_tuple("Extension")
+                .field(&fmt::from_fn(|f| {
+                    f.debug_struct("Unknown")
+                        .field("kind", kind)
+                        .field("contents", &length(contents))
+                        .finish()
+                }))
+                .finish(),
+            Request::Unknown { kind, contents } => f
+                .debug_struct("Unknown")
+                .field("kind", kind)
+                .field("contents", &length(contents))
+                .finish(),
+            // Identities being added and lock passphrases are always hidden
+            request => request.fmt(f),
+        }
+    }
+}
+
+impl Debug for Logged<'_, Response> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if !redacting() {
+            return self.0.fmt(f);
+        }
+        match self.0 {
+            Response::Identities { keys } => f
+                .debug_struct("Identities")
+                .field(
+                    "keys",
+                    &fmt::from_fn(|f| f.debug_list().entries(keys.iter().map(Logged)).finish()),
+                )
+                .finish(),
+            Response::SignResponse { signature } => f
+                .debug_struct("SignResponse")
+                .field("signature", &length(signature))
+                .finish(),
+            // Passed through responses could be anything
+            Response::Success { contents } => f
+                .debug_struct("Success")
+                .field("contents", &length(contents))
+                .finish(),
+            Response::Failure { contents } => f
+                .debug_struct("Failure")
+                .field("contents", &length(contents))
+                .finish(),
+            Response::ExtensionFailure { contents } => f
+                .debug_struct("ExtensionFailure")
+                .field("contents", &length(contents))
+                .finish(),
+            Response::Unknown { kind, contents } => f
+                .debug_struct("Unknown")
+                .field("kind", kind)
+                .field("contents", &length(contents))
+                .finish(),
+            Response::Extension(ExtensionResponse::UpstreamDetails(details)) => f
+                .debug_tuple("Extension")
+                .field(&fmt::from_fn(|f| {
+                    f.debug_struct("UpstreamDetails")
+                        .field("preferred", &details.preferred)
+                        .field("upstreams", &details.upstreams.len())
+                        .finish_non_exhaustive()
+                }))
+                .finish(),
+            response => response.fmt(f),
+        }
+    }
+}
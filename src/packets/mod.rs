@@ -1,25 +1,79 @@
 use bytes::{Bytes, BytesMut};
 use eyre::Error;
 
+mod certificate;
 mod codec;
 mod extension;
+mod identity;
+mod logged;
 mod request;
 mod response;
+mod sign_data;
 mod util;
 
 pub(crate) use self::{
-    codec::Codec,
-    extension::{ErrorMsg, Extension, ExtensionResponse, NoResponse, UpstreamListV2},
+    certificate::{certificates_first, Certificate, Validity},
+    codec::{Codec, Frame},
+    extension::{
+        DaemonStatus, ErrorMsg, Extension, ExtensionResponse, NoResponse, SessionBind, Stats,
+        UpstreamDetail, UpstreamDetails, UpstreamListV2, UpstreamStatus,
+    },
+    identity::{Constraint, Identity},
+    logged::{redact_logs, Logged},
     request::Request,
     response::Response,
+    sign_data::SignData,
 };
 
-#[derive(Debug, Eq, PartialEq, Hash)]
+/// Sign request flags asking for an `ssh-rsa` key to use SHA-2 instead of SHA-1
+pub(crate) const SSH_AGENT_RSA_SHA2_256: u32 = 0x02;
+pub(crate) const SSH_AGENT_RSA_SHA2_512: u32 = 0x04;
+
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
 pub(crate) struct PublicKey {
     pub(crate) blob: Bytes,
     pub(crate) comment: Bytes,
 }
 
+impl PublicKey {
+    /// The `SHA256:...` fingerprint, the same as `ssh-add -l` shows
+    pub(crate) fn fingerprint(&self) -> String {
+        fingerprint(&self.blob)
+    }
+
+    /// The certificate details, if this is a `*-cert-v01@openssh.com` key
+    pub(crate) fn certificate(&self) -> Option<Certificate> {
+        Certificate::parse(&self.blob)
+    }
+
+    pub(crate) fn comment(&self) -> std::borrow::Cow<'_, str> {
+        String::from_utf8_lossy(&self.comment)
+    }
+}
+
+/// The key type name that a public key blob starts with, e.g. `ssh-ed25519`
+pub(crate) fn key_type(blob: &Bytes) -> Option<String> {
+    use util::BytesExt as _;
+
+    blob.clone().try_get_utf8_string()?.ok()
+}
+
+/// Security key types need a human to touch the key for every signature
+pub(crate) fn is_security_key(blob: &Bytes) -> bool {
+    key_type(blob).is_some_and(|key_type| key_type.starts_with("sk-"))
+}
+
+/// The `SHA256:...` fingerprint of a public key blob
+pub(crate) fn fingerprint(blob: &[u8]) -> String {
+    use base64::Engine as _;
+    use sha2::Digest as _;
+
+    format!(
+        "SHA256:{}",
+        base64::engine::general_purpose::STANDARD_NO_PAD.encode(sha2::Sha256::digest(blob))
+    )
+}
+
 pub(crate) trait Parse: Sized {
     #[culpa::throws]
     fn parse(kind: u8, contents: Bytes) -> Self;
@@ -14,15 +14,13 @@ const SSH_AGENTC_ADD_ID_CONSTRAINED: u8 = 25;
 const SSH_AGENTC_REMOVE_IDENTITY: u8 = 18;
 const SSH_AGENTC_REMOVE_ALL_IDENTITIES: u8 = 19;
 const SSH_AGENTC_EXTENSION: u8 = 27;
-/*
-const SSH_AGENTC_ADD_SMARTCARD_KEY: u8 = 20;
-const SSH_AGENTC_REMOVE_SMARTCARD_KEY: u8 = 21;
 const SSH_AGENTC_LOCK: u8 = 22;
 const SSH_AGENTC_UNLOCK: u8 = 23;
+const SSH_AGENTC_ADD_SMARTCARD_KEY: u8 = 20;
+const SSH_AGENTC_REMOVE_SMARTCARD_KEY: u8 = 21;
 const SSH_AGENTC_ADD_SMARTCARD_KEY_CONSTRAINED: u8 = 26;
-*/
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 #[allow(dead_code)] // some variants are unused
 #[allow(clippy::enum_variant_names)] // following the specification names
 pub(crate) enum Request {
@@ -44,6 +42,12 @@ pub(crate) enum Request {
         blob: Bytes,
     },
     RemoveAllIdentities,
+    Lock {
+        passphrase: SecretBytesMut,
+    },
+    Unlock {
+        passphrase: SecretBytesMut,
+    },
     Extension(Extension),
     Unknown {
         kind: u8,
@@ -60,10 +64,42 @@ impl Request {
             Self::AddIdConstrained { .. } => SSH_AGENTC_ADD_ID_CONSTRAINED,
             Self::RemoveIdentity { .. } => SSH_AGENTC_REMOVE_IDENTITY,
             Self::RemoveAllIdentities => SSH_AGENTC_REMOVE_ALL_IDENTITIES,
+            Self::Lock { .. } => SSH_AGENTC_LOCK,
+            Self::Unlock { .. } => SSH_AGENTC_UNLOCK,
             Self::Extension(..) => SSH_AGENTC_EXTENSION,
             Self::Unknown { kind, .. } => *kind,
         }
     }
+
+    /// Whether requests of this type contain private keys, passphrases or PKCS#11 PINs
+    pub(crate) fn carries_secrets(kind: u8) -> bool {
+        matches!(
+            kind,
+            SSH_AGENTC_ADD_IDENTITY
+                | SSH_AGENTC_ADD_ID_CONSTRAINED
+                | SSH_AGENTC_LOCK
+                | SSH_AGENTC_UNLOCK
+                | SSH_AGENTC_ADD_SMARTCARD_KEY
+                | SSH_AGENTC_REMOVE_SMARTCARD_KEY
+                | SSH_AGENTC_ADD_SMARTCARD_KEY_CONSTRAINED
+        )
+    }
+
+    /// The name of the message type, for metrics
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            Self::RequestIdentities => "request_identities",
+            Self::SignRequest { .. } => "sign_request",
+            Self::AddIdentity { .. } => "add_identity",
+            Self::AddIdConstrained { .. } => "add_id_constrained",
+            Self::RemoveIdentity { .. } => "remove_identity",
+            Self::RemoveAllIdentities => "remove_all_identities",
+            Self::Lock { .. } => "lock",
+            Self::Unlock { .. } => "unlock",
+            Self::Extension(..) => "extension",
+            Self::Unknown { .. } => "unknown",
+        }
+    }
 }
 
 impl Parse for Request {
@@ -111,6 +147,19 @@ impl Parse for Request {
                 Self::Extension(Extension::parse(kind, contents)?)
             }
             SSH_AGENTC_REMOVE_ALL_IDENTITIES => Self::RemoveAllIdentities,
+            SSH_AGENTC_LOCK | SSH_AGENTC_UNLOCK => {
+                let passphrase = SecretBytesMut::new(
+                    contents
+                        .try_get_string()
+                        .ok_or_else(|| eyre!("missing passphrase"))?
+                        .as_ref(),
+                );
+                if kind == SSH_AGENTC_LOCK {
+                    Self::Lock { passphrase }
+                } else {
+                    Self::Unlock { passphrase }
+                }
+            }
             _ => {
                 let contents = contents.split_to(contents.len());
                 Self::Unknown { kind, contents }
@@ -142,6 +191,9 @@ impl Encode for Request {
             Self::RemoveIdentity { blob } => {
                 dst.try_put_string(blob)?;
             }
+            Self::Lock { passphrase } | Self::Unlock { passphrase } => {
+                dst.try_put_string(passphrase.expose_secret().as_ref())?;
+            }
             Self::Extension(extension) => {
                 extension.encode_to(dst)?;
             }
@@ -160,6 +212,9 @@ impl Encode for Request {
                 4 + key_type.len() + contents.expose_secret().len()
             }
             Self::RemoveIdentity { blob } => 4 + blob.len(),
+            Self::Lock { passphrase } | Self::Unlock { passphrase } => {
+                4 + passphrase.expose_secret().len()
+            }
             Self::Extension(extension) => extension.encoded_length_estimate(),
             Self::Unknown { contents, .. } => contents.len(),
         }
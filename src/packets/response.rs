@@ -14,6 +14,7 @@ pub(super) const SSH_AGENT_EXTENSION_FAILURE: u8 = 28;
 
 #[derive(Debug)]
 #[allow(dead_code)] // some variants are unused
+#[allow(clippy::enum_variant_names)] // following the specification names
 pub(crate) enum Response {
     Success { contents: Bytes },
     Failure { contents: Bytes },
@@ -52,6 +53,19 @@ impl Response {
         }
     }
 
+    /// The name of the message type, following the specification
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            Self::Success { .. } => "success",
+            Self::Failure { .. } => "failure",
+            Self::Identities { .. } => "identities_answer",
+            Self::SignResponse { .. } => "sign_response",
+            Self::Extension(..) => "extension",
+            Self::ExtensionFailure { .. } => "extension_failure",
+            Self::Unknown { .. } => "unknown",
+        }
+    }
+
     /// Same as `try_parse_extension`, but returns an error if the server didn't understand the
     /// extension.
     #[culpa::throws]
@@ -0,0 +1,190 @@
+use bytes::Bytes;
+
+use super::{fingerprint, util::BytesExt};
+
+const SSH_MSG_USERAUTH_REQUEST: u8 = 50;
+const SSHSIG_MAGIC: &[u8] = b"SSHSIG";
+
+/// The decoded data of a sign request, in one of the formats OpenSSH signs
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum SignData {
+    /// A `SSH_MSG_USERAUTH_REQUEST` from `ssh` authenticating a connection
+    UserAuth {
+        session_id: Bytes,
+        user: String,
+        service: String,
+        /// The public key algorithm, e.g. `rsa-sha2-512`
+        algorithm: String,
+        key: Bytes,
+        /// The key of the host being authenticated to, only sent when using the
+        /// `publickey-hostbound-v00@openssh.com` method
+        host_key: Option<Bytes>,
+    },
+    /// A `ssh-keygen -Y sign` signature, such as a git commit signature
+    SshSig {
+        /// Separates signatures for different purposes, e.g. `git` or `file`
+        namespace: String,
+        /// The algorithm used to hash the signed message, e.g. `sha512`
+        hash_algorithm: String,
+        hash: Bytes,
+    },
+}
+
+impl SignData {
+    /// Returns `None` if the data isn't in a recognised format
+    pub(crate) fn parse(data: &Bytes) -> Option<Self> {
+        let mut data = data.clone();
+        let parsed = if data.starts_with(SSHSIG_MAGIC) {
+            let _ = data.split_to(SSHSIG_MAGIC.len());
+            let namespace = data.try_get_utf8_string()?.ok()?;
+            let _reserved = data.try_get_string()?;
+            let hash_algorithm = data.try_get_utf8_string()?.ok()?;
+            let hash = data.try_get_string()?;
+            Self::SshSig {
+                namespace,
+                hash_algorithm,
+                hash,
+            }
+        } else {
+            let session_id = data.try_get_string()?;
+            if data.try_get_u8()? != SSH_MSG_USERAUTH_REQUEST {
+                return None;
+            }
+            let user = data.try_get_utf8_string()?.ok()?;
+            let service = data.try_get_utf8_string()?.ok()?;
+            let method = data.try_get_utf8_string()?.ok()?;
+            let hostbound = match method.as_str() {
+                "publickey" => false,
+                "publickey-hostbound-v00@openssh.com" => true,
+                _ => return None,
+            };
+            // Whether this has a signature, which it always does when it's being signed
+            if data.try_get_u8()? != 1 {
+                return None;
+            }
+            let algorithm = data.try_get_utf8_string()?.ok()?;
+            let key = data.try_get_string()?;
+            let host_key = if hostbound {
+                Some(data.try_get_string()?)
+            } else {
+                None
+            };
+            Self::UserAuth {
+                session_id,
+                user,
+                service,
+                algorithm,
+                key,
+                host_key,
+            }
+        };
+        data.is_empty().then_some(parsed)
+    }
+
+    /// The `SSHSIG` namespace, if this is one
+    pub(crate) fn namespace(&self) -> Option<&str> {
+        match self {
+            Self::SshSig { namespace, .. } => Some(namespace),
+            Self::UserAuth { .. } => None,
+        }
+    }
+}
+
+impl std::fmt::Display for SignData {
+    #[culpa::throws(std::fmt::Error)]
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) {
+        match self {
+            Self::UserAuth { user, host_key, .. } => {
+                write!(f, "login as {user}")?;
+                if let Some(host_key) = host_key {
+                    write!(f, " to host {}", fingerprint(host_key))?;
+                }
+            }
+            Self::SshSig { namespace, .. } => write!(f, "{namespace} signature")?,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::{BufMut, Bytes, BytesMut};
+
+    use super::{SignData, SSHSIG_MAGIC, SSH_MSG_USERAUTH_REQUEST};
+
+    fn put_string(buf: &mut BytesMut, value: &[u8]) {
+        buf.put_u32(value.len().try_into().unwrap());
+        buf.put_slice(value);
+    }
+
+    fn sshsig() -> BytesMut {
+        let mut buf = BytesMut::new();
+        buf.put_slice(SSHSIG_MAGIC);
+        put_string(&mut buf, b"git");
+        put_string(&mut buf, b"");
+        put_string(&mut buf, b"sha512");
+        put_string(&mut buf, &[0xaa; 64]);
+        buf
+    }
+
+    fn hostbound_userauth() -> BytesMut {
+        let mut buf = BytesMut::new();
+        put_string(&mut buf, b"session id");
+        buf.put_u8(SSH_MSG_USERAUTH_REQUEST);
+        put_string(&mut buf, b"git");
+        put_string(&mut buf, b"ssh-connection");
+        put_string(&mut buf, b"publickey-hostbound-v00@openssh.com");
+        buf.put_u8(1);
+        put_string(&mut buf, b"ssh-ed25519");
+        put_string(&mut buf, b"user key");
+        put_string(&mut buf, b"host key");
+        buf
+    }
+
+    #[test]
+    fn parses_sshsig() {
+        let data = SignData::parse(&sshsig().freeze()).unwrap();
+        assert_eq!(
+            data,
+            SignData::SshSig {
+                namespace: "git".to_owned(),
+                hash_algorithm: "sha512".to_owned(),
+                hash: Bytes::from_static(&[0xaa; 64]),
+            }
+        );
+        assert_eq!(data.namespace(), Some("git"));
+    }
+
+    #[test]
+    fn parses_hostbound_userauth() {
+        let data = SignData::parse(&hostbound_userauth().freeze()).unwrap();
+        assert_eq!(
+            data,
+            SignData::UserAuth {
+                session_id: Bytes::from_static(b"session id"),
+                user: "git".to_owned(),
+                service: "ssh-connection".to_owned(),
+                algorithm: "ssh-ed25519".to_owned(),
+                key: Bytes::from_static(b"user key"),
+                host_key: Some(Bytes::from_static(b"host key")),
+            }
+        );
+        assert_eq!(data.namespace(), None);
+    }
+
+    #[test]
+    fn rejects_truncated() {
+        for full in [sshsig().freeze(), hostbound_userauth().freeze()] {
+            for len in 0..full.len() {
+                assert_eq!(SignData::parse(&full.slice(..len)), None, "length {len}");
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_trailing_bytes() {
+        for mut full in [sshsig(), hostbound_userauth()] {
+            full.put_u8(0);
+            assert_eq!(SignData::parse(&full.freeze()), None);
+        }
+    }
+}
@@ -1,10 +1,13 @@
 use bytes::{Buf, BufMut, Bytes, BytesMut};
 use eyre::{bail, eyre, Error};
-use std::rc::Rc;
+use std::{rc::Rc, time::Duration};
 
 pub(super) trait BytesExt: Sized {
     fn try_get_u8(&mut self) -> Option<u8>;
     fn try_get_u32_be(&mut self) -> Option<u32>;
+    fn try_get_u64_be(&mut self) -> Option<u64>;
+    /// Durations are sent as a number of milliseconds
+    fn try_get_duration(&mut self) -> Option<Duration>;
     fn try_get_string(&mut self) -> Option<Self>;
     fn try_get_utf8_string(&mut self) -> Option<Result<String, Error>>;
     fn try_get_utf8_string_rc(&mut self) -> Option<Result<Rc<str>, Error>>;
@@ -20,6 +23,14 @@ impl BytesExt for Bytes {
         (self.len() >= std::mem::size_of::<u32>()).then(|| self.get_u32())
     }
 
+    fn try_get_u64_be(&mut self) -> Option<u64> {
+        (self.len() >= std::mem::size_of::<u64>()).then(|| self.get_u64())
+    }
+
+    fn try_get_duration(&mut self) -> Option<Duration> {
+        self.try_get_u64_be().map(Duration::from_millis)
+    }
+
     fn try_get_string(&mut self) -> Option<Self> {
         let length = usize::try_from(self.try_get_u32_be()?).ok()?;
         (self.len() >= length).then(|| self.split_to(length))
@@ -54,6 +65,14 @@ impl BytesExt for BytesMut {
         (self.len() >= std::mem::size_of::<u32>()).then(|| self.get_u32())
     }
 
+    fn try_get_u64_be(&mut self) -> Option<u64> {
+        (self.len() >= std::mem::size_of::<u64>()).then(|| self.get_u64())
+    }
+
+    fn try_get_duration(&mut self) -> Option<Duration> {
+        self.try_get_u64_be().map(Duration::from_millis)
+    }
+
     fn try_get_string(&mut self) -> Option<Self> {
         let length = usize::try_from(self.try_get_u32_be()?).ok()?;
         (self.len() >= length).then(|| self.split_to(length))
@@ -87,6 +106,10 @@ pub(super) trait BytesMutExt: Sized {
     #[culpa::throws]
     fn try_put_u32_be(&mut self, n: u32);
     #[culpa::throws]
+    fn try_put_u64_be(&mut self, n: u64);
+    #[culpa::throws]
+    fn try_put_duration(&mut self, duration: Duration);
+    #[culpa::throws]
     fn try_put_string(&mut self, string: impl Buf);
     #[culpa::throws]
     fn try_put_bool(&mut self, v: bool);
@@ -117,6 +140,19 @@ impl BytesMutExt for BytesMut {
         self.put_u32(n)
     }
 
+    #[culpa::throws]
+    fn try_put_u64_be(&mut self, n: u64) {
+        if self.remaining_mut() < std::mem::size_of::<u64>() {
+            bail!("not enough space remaining");
+        }
+        self.put_u64(n)
+    }
+
+    #[culpa::throws]
+    fn try_put_duration(&mut self, duration: Duration) {
+        self.try_put_u64_be(u64::try_from(duration.as_millis())?)?;
+    }
+
     #[culpa::throws]
     fn try_put_string(&mut self, string: impl Buf) {
         self.try_put_u32_be(u32::try_from(string.remaining())?)?;
@@ -1,106 +1,718 @@
-use eyre::{bail, Context as _, Error};
+use bytes::Bytes;
+use eyre::{eyre, Error};
 
 use futures::{
-    sink::SinkExt,
-    stream::{StreamExt, TryStreamExt},
+    future::{self, Either},
+    sink::{Sink, SinkExt},
+    stream::{Stream, StreamExt, TryStreamExt},
 };
-use std::{pin::pin, rc::Rc};
-use tokio::net::UnixStream;
-use tokio_util::codec::Framed;
+use secrecy::ExposeSecret as _;
+use std::{
+    cell::{Cell, RefCell},
+    os::fd::AsFd as _,
+    pin::{pin, Pin},
+    rc::Rc,
+    time::{Instant, SystemTime},
+};
+use tokio::{io::Interest, net::UnixStream, sync::broadcast};
+use tokio_util::codec::{FramedRead, FramedWrite};
 
 use crate::{
     app::Context,
-    client::Client,
-    packets::{Codec, Extension, ExtensionResponse, Request, Response, UpstreamListV2},
+    askpass,
+    audit::{Event, Requester},
+    config::Config,
+    known_hosts::Host,
+    monitor::{self, EventKind},
+    net::Peer,
+    packets::{
+        self, Codec, Extension, ExtensionResponse, Identity, Logged, Request, Response,
+        SessionBind, SignData, Stats, UpstreamListV2, Validity,
+    },
+    verify,
 };
 
+/// Which of the daemon's sockets a connection was accepted on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Socket {
+    /// Serves both the agent protocol and our management extensions, used when there's no
+    /// separate control socket
+    Combined,
+    /// Only serves the agent protocol
+    Agent,
+    /// Also serves our management extensions
+    Control,
+}
+
+/// What we know about the client on a connection
+struct Session {
+    /// The same id as in the connection's tracing span and monitor events
+    id: u64,
+    peer: Peer,
+    /// The host key fingerprint from the last `session-bind@openssh.com` extension
+    destination: RefCell<Option<String>>,
+    /// The session id from that session bind
+    session_id: RefCell<Option<Bytes>>,
+    /// Whether that session bind was for forwarding the agent, requests then come from processes
+    /// on the destination rather than logging in to it
+    forwarding: Cell<bool>,
+}
+
+impl Session {
+    /// The host the connection is bound to, if it isn't being forwarded from it
+    fn bound_host(&self, config: &Config) -> Option<Host> {
+        if self.forwarding.get() {
+            return None;
+        }
+        let destination = self.destination.borrow().clone()?;
+        Some(Host::lookup(
+            destination,
+            &config.destinations.known_hosts_files(),
+        ))
+    }
+
+    /// The host a sign request is logging in to, only known for user authentication requests on a
+    /// bound connection
+    fn login_host(&self, config: &Config, signing: Option<&SignData>) -> Option<Host> {
+        let Some(SignData::UserAuth { .. }) = signing else {
+            return None;
+        };
+        self.bound_host(config)
+    }
+
+    /// User authentication on a connection bound for authentication must be for that session, and
+    /// name the same host if it includes one, the same as `ssh-agent` checks
+    fn check_login(&self, signing: Option<&SignData>) -> Result<(), &'static str> {
+        let Some(SignData::UserAuth {
+            session_id,
+            host_key,
+            ..
+        }) = signing
+        else {
+            return Ok(());
+        };
+        if self.forwarding.get() {
+            return Ok(());
+        }
+        let (Some(destination), Some(bound_id)) =
+            (&*self.destination.borrow(), &*self.session_id.borrow())
+        else {
+            return Ok(());
+        };
+        if session_id != bound_id {
+            tracing::warn!(
+                destination,
+                "user authentication is for a different session than the connection is bound to"
+            );
+            return Err("user authentication is for a different session");
+        }
+        if let Some(host_key) = host_key {
+            let signing_to = packets::fingerprint(host_key);
+            if signing_to != *destination {
+                tracing::warn!(
+                    destination,
+                    signing_to,
+                    "user authentication is for a different host than the session is bound to"
+                );
+                return Err("user authentication is for a different host");
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks a `session-bind@openssh.com` before accepting it, the host must have signed the
+    /// session id, and like `ssh-agent` a connection that was bound for authentication can't be
+    /// bound again
+    fn check_bind(&self, bind: &SessionBind) -> Result<(), &'static str> {
+        if self.destination.borrow().is_some() && !self.forwarding.get() {
+            return Err("the connection is already bound for authentication");
+        }
+        match verify::host_signature(&bind.host_key, &bind.session_id, &bind.signature) {
+            Ok(true) => Ok(()),
+            Ok(false) => Err("the host's signature can't be verified"),
+            Err(e) => {
+                tracing::debug!("invalid session bind signature: {e:?}");
+                Err("the host's signature is invalid")
+            }
+        }
+    }
+}
+
+impl Socket {
+    fn serves_management(self) -> bool {
+        match self {
+            Self::Combined | Self::Control => true,
+            Self::Agent => false,
+        }
+    }
+}
+
 #[culpa::throws]
-pub(crate) async fn handle(stream: UnixStream, context: Rc<Context>) {
+pub(crate) async fn handle(
+    stream: UnixStream,
+    context: Rc<Context>,
+    socket: Socket,
+    id: u64,
+    peer: Peer,
+) {
     tracing::debug!("new client connection");
+    let session = Session {
+        id,
+        peer,
+        destination: RefCell::new(None),
+        session_id: RefCell::new(None),
+        forwarding: Cell::new(false),
+    };
+
+    let tap = context.capture.client(id);
+    // A separate registration of the same socket, so waiting for it to close doesn't disturb the
+    // readiness of writing responses
+    let hangup = UnixStream::from_std(stream.as_fd().try_clone_to_owned()?.into())?;
+    let (read, write) = stream.into_split();
+    let mut requests = pin!(FramedRead::new(
+        read,
+        Codec::<Request, Response>::new().tap(Some(tap.clone()))
+    )
+    .take_until(context.shutdown.clone())
+    .inspect_ok(|request| tracing::debug!(request = ?Logged(request), "received"))
+    .peekable());
+    let mut responses = pin!(FramedWrite::new(
+        write,
+        Codec::<Request, Response>::new().tap(Some(tap))
+    )
+    .with(|response| {
+        tracing::debug!(response = ?Logged(&response), "sending");
+        async move { Ok::<_, Error>(response) }
+    }));
+
+    while let Some(message) = requests.next().await.transpose()? {
+        let monitor = matches!(message, Request::Extension(Extension::Monitor));
+        let mut response = pin!(respond(message, &context, socket, &session));
 
-    let mut messages = pin!(Framed::new(stream, Codec::<Request, Response>::new())
-        .take_until(context.shutdown.clone())
-        .inspect_ok(|request| tracing::debug!(?request, "received"))
-        .with(|response| {
-            tracing::debug!(?response, "sending");
-            async move { Ok::<_, Error>(response) }
-        }));
-
-    while let Some(message) = messages.next().await.transpose()? {
-        match message {
-            Request::RequestIdentities => {
-                tracing::info!("processing identities request");
-                let keys = context.upstreams.request_identities().await?;
-                messages.send(Response::Identities { keys }).await?;
+        // Watch for the client going away while we wait on upstreams, dropping the response
+        // future closes the upstream connections so they can abort too (e.g. stop waiting for a
+        // security key touch)
+        let response = match future::select(response.as_mut(), requests.as_mut().peek()).await {
+            Either::Left((response, _)) => response?,
+            // Once shutdown is requested the requests stream ends, but we still let the current
+            // request finish
+            Either::Right((None, _)) if context.shutdown.peek().is_some() => response.await?,
+            // The client may have only shut down its write side and still be waiting for the
+            // response, so only cancel once it's gone entirely
+            Either::Right((None, _)) => {
+                match future::select(response.as_mut(), pin!(hung_up(&hangup))).await {
+                    Either::Left((response, _)) => response?,
+                    Either::Right(_) => {
+                        tracing::info!("client disconnected, cancelling in-flight request");
+                        return;
+                    }
+                }
             }
-            Request::AddIdentity { .. }
-            | Request::AddIdConstrained { .. }
-            | Request::RemoveIdentity { .. }
-            | Request::RemoveAllIdentities { .. } => {
-                tracing::info!("processing {message:?}");
-                let response = context.upstreams.forward_to_adds(message).await?;
-                messages.send(response).await?;
+            Either::Right((Some(Err(_)), _)) => {
+                tracing::info!("client disconnected, cancelling in-flight request");
+                return;
             }
-            Request::SignRequest { blob, data, flags } => {
-                tracing::info!("processing sign request");
-                let signature = context.upstreams.sign_request(blob, data, flags).await;
-                messages
-                    .send(
-                        signature
-                            .map(|signature| Response::SignResponse { signature })
-                            .unwrap_or(Response::FAILURE),
-                    )
-                    .await?;
+            // The client sent another request without waiting for the response, it will be
+            // handled once this one is done
+            Either::Right((Some(Ok(_)), _)) => response.await?,
+        };
+        context.events.emit(EventKind::Responded {
+            connection: session.id,
+            success: !matches!(
+                response,
+                Response::Failure { .. }
+                    | Response::ExtensionFailure { .. }
+                    | Response::Extension(ExtensionResponse::Error(_))
+            ),
+        });
+
+        if monitor && matches!(response, Response::Success { .. }) {
+            // Subscribe before acknowledging, so the client sees everything after its request
+            let events = context.events.subscribe();
+            responses.send(response).await?;
+            stream_events(events, requests.as_mut(), responses.as_mut()).await?;
+            tracing::debug!("monitor client unsubscribed");
+            return;
+        }
+
+        responses.send(response).await?;
+    }
+
+    tracing::debug!("client connection closed");
+}
+
+#[culpa::throws]
+async fn respond(
+    message: Request,
+    context: &Context,
+    socket: Socket,
+    session: &Session,
+) -> Response {
+    context.metrics.request(message.name());
+    context.events.emit(EventKind::Request {
+        connection: session.id,
+        kind: match &message {
+            Request::Extension(extension) => extension.kind().to_owned(),
+            _ => message.name().to_owned(),
+        },
+        fingerprint: match &message {
+            Request::SignRequest { blob, .. } | Request::RemoveIdentity { blob } => {
+                Some(packets::fingerprint(blob))
             }
-            Request::Extension(Extension::AddUpstreamV2(upstream)) => {
-                let client = Client::from(upstream.clone());
-                match async {
-                    if Some(upstream.path.as_ref()) == context.path.borrow().as_deref() {
-                        bail!("attempted to add self as upstream");
+            _ => None,
+        },
+    });
+
+    if let Request::Extension(extension) = &message {
+        if extension.is_management() && !socket.serves_management() {
+            tracing::warn!(
+                kind = extension.kind(),
+                "refusing management extension, it is only served on the control socket"
+            );
+            return Response::FAILURE;
+        }
+    }
+
+    match message {
+        Request::RequestIdentities => {
+            tracing::info!("processing identities request");
+            let config = context.config();
+            // Reading the known hosts files is only needed when there are rules
+            let host = if config.destinations.rules.is_empty() {
+                None
+            } else {
+                session.bound_host(&config)
+            };
+            let now = SystemTime::now();
+            let keys = context
+                .upstreams
+                .request_identities(|upstream, key| {
+                    if config.certificates.hide_invalid {
+                        if let Some(certificate) = key.certificate() {
+                            let validity = certificate.validity(now);
+                            if validity != Validity::Valid {
+                                tracing::debug!(
+                                    fingerprint = key.fingerprint(),
+                                    upstream,
+                                    %validity,
+                                    "hiding certificate"
+                                );
+                                return false;
+                            }
+                        }
                     }
-                    tracing::info!(%upstream.path, upstream.forward_adds, "adding upstream");
-                    client
-                        .request_identities()
-                        .await
-                        .context("failed to test connection")?;
-                    Ok(())
+                    let Some(host) = &host else {
+                        return true;
+                    };
+                    let fingerprint = key.fingerprint();
+                    let denied = config
+                        .destinations
+                        .denied_by(&fingerprint, upstream, Some(host));
+                    if let Some(rule) = denied {
+                        tracing::debug!(
+                            fingerprint,
+                            upstream,
+                            rule,
+                            %host,
+                            "hiding identity, denied for the destination by rule"
+                        );
+                    }
+                    denied.is_none()
+                })
+                .await?;
+            Response::Identities { keys }
+        }
+        Request::AddIdentity {
+            ref key_type,
+            ref contents,
+        }
+        | Request::AddIdConstrained {
+            ref key_type,
+            ref contents,
+        } => {
+            tracing::info!("processing {:?}", Logged(&message));
+            let key_type = key_type.clone();
+            let constrained = matches!(message, Request::AddIdConstrained { .. });
+            let fingerprint = Identity::parse(&key_type, contents.expose_secret(), constrained)
+                .and_then(|identity| identity.public_blob())
+                .ok()
+                .map(|blob| packets::fingerprint(&blob));
+            let response = context.upstreams.forward_add(message).await;
+            context.audit.record(Event::Add {
+                requester: Requester::from(&session.peer),
+                key_type,
+                fingerprint,
+                constrained,
+                success: matches!(response, Ok(Response::Success { .. })),
+            });
+            response?
+        }
+        Request::RemoveIdentity { blob } => {
+            let fingerprint = packets::fingerprint(&blob);
+            tracing::info!(fingerprint, "processing remove identity");
+            let response = context.upstreams.remove_identity(blob).await;
+            context.audit.record(Event::Remove {
+                requester: Requester::from(&session.peer),
+                fingerprint,
+                success: matches!(response, Ok(Response::Success { .. })),
+            });
+            response?
+        }
+        Request::RemoveAllIdentities => {
+            tracing::info!("processing remove all identities");
+            let response = context.upstreams.remove_all_identities().await;
+            context.audit.record(Event::RemoveAll {
+                requester: Requester::from(&session.peer),
+                success: matches!(response, Ok(Response::Success { .. })),
+            });
+            response?
+        }
+        Request::SignRequest { blob, data, flags } => {
+            let fingerprint = packets::fingerprint(&blob);
+            let signing = SignData::parse(&data);
+            tracing::info!(
+                fingerprint,
+                flags,
+                signing = signing.as_ref().map(tracing::field::display),
+                "processing sign request"
+            );
+            let audit = SignAudit {
+                context,
+                session,
+                fingerprint: fingerprint.clone(),
+                flags,
+                signing: signing.as_ref().map(|signing| signing.to_string()),
+                started: Instant::now(),
+                outcome: None,
+            };
+            let config = context.config();
+            let rule = signing
+                .as_ref()
+                .and_then(SignData::namespace)
+                .and_then(|namespace| config.signing.rule(&fingerprint, namespace));
+            // SSHSIG signatures can't be used to log in, so only the signing rules apply to them
+            let checked = !matches!(signing, Some(SignData::SshSig { .. }))
+                && config.destinations.restricts(&fingerprint);
+            let host = checked
+                .then(|| session.login_host(&config, signing.as_ref()))
+                .flatten();
+            let destination_denied = |upstream: &str| {
+                checked
+                    && config
+                        .destinations
+                        .denied_by(&fingerprint, upstream, host.as_ref())
+                        .is_some()
+            };
+            // Refused outright if every upstream holding the key is denied, otherwise only those
+            // that aren't are asked
+            let destination_rule = if checked {
+                let (holders, _) = context.upstreams.holders(&blob).await;
+                holders
+                    .iter()
+                    .map(|holder| {
+                        config
+                            .destinations
+                            .denied_by(&fingerprint, holder, host.as_ref())
+                    })
+                    .collect::<Option<Vec<_>>>()
+                    .and_then(|rules| rules.first().copied())
+            } else {
+                None
+            };
+            let signed = match (
+                session.check_login(signing.as_ref()),
+                rule,
+                destination_rule,
+            ) {
+                (Err(error), _, _) => {
+                    tracing::info!(fingerprint, "refusing sign request, {error}");
+                    Err(error)
                 }
-                .await
-                {
-                    Ok(()) => {
-                        context.upstreams.add(client).await;
-                        messages.send(Response::SUCCESS).await?;
+                (_, Some((i, rule)), _) if !rule.allow => {
+                    tracing::info!(
+                        fingerprint,
+                        rule = i,
+                        "refusing sign request, denied by rule"
+                    );
+                    Err("denied by a signing rule")
+                }
+                (_, _, Some(i)) => {
+                    tracing::info!(
+                        fingerprint,
+                        rule = i,
+                        host = host.as_ref().map(tracing::field::display),
+                        "refusing sign request, denied for the destination by rule"
+                    );
+                    Err("denied by a destination rule")
+                }
+                _ => match confirm_sign(context, session, &blob, signing.as_ref()).await {
+                    Ok(true) => {
+                        let only = rule.map(|(_, rule)| rule.upstreams.as_slice());
+                        let allowed = |upstream: &str| {
+                            only.is_none_or(|only| {
+                                only.is_empty() || only.iter().any(|path| path == upstream)
+                            }) && !destination_denied(upstream)
+                        };
+                        context
+                            .upstreams
+                            .sign_request(blob, data, flags, allowed)
+                            .await
+                            .ok_or("no upstream signed the request")
                     }
+                    Ok(false) => Err("declined by the user"),
                     Err(e) => {
-                        tracing::warn!("sending error back to client: {e:?}");
-                        messages
-                            .send(Response::Extension(ExtensionResponse::Error(e.into())))
-                            .await?;
+                        tracing::warn!(
+                            fingerprint,
+                            "failed to confirm sign request, refusing it: {e:?}"
+                        );
+                        Err("failed to ask the user for confirmation")
                     }
+                },
+            };
+            audit.finish(match &signed {
+                Ok((upstream, _)) => Ok(upstream.to_string()),
+                Err(error) => Err(error.to_string()),
+            });
+            match signed {
+                Ok((_, signature)) => Response::SignResponse { signature },
+                Err(_) => Response::FAILURE,
+            }
+        }
+        Request::Lock { .. } | Request::Unlock { .. } => {
+            tracing::info!(kind = message.kind(), "processing lock request");
+            context.upstreams.lock(message).await
+        }
+        Request::Extension(
+            Extension::AddUpstreamV2(upstream) | Extension::AddUpstreamV3(upstream),
+        ) => {
+            tracing::info!(%upstream.path, upstream.forward_adds, ?upstream.timeouts, "adding upstream");
+            match context.add_upstream(upstream).await {
+                Ok(()) => Response::SUCCESS,
+                Err(e) => {
+                    tracing::warn!("sending error back to client: {e:?}");
+                    Response::Extension(ExtensionResponse::Error(e.into()))
                 }
             }
-            Request::Extension(Extension::ListUpstreamsV2) => {
-                tracing::info!("processing upstreams v2 request");
-                let upstreams = context.upstreams.list();
-                messages
-                    .send(Response::Extension(ExtensionResponse::UpstreamListV2(
-                        UpstreamListV2 { upstreams },
-                    )))
-                    .await?;
+        }
+        Request::Extension(Extension::ListUpstreamsV2) => {
+            tracing::info!("processing upstreams v2 request");
+            let upstreams = context.upstreams.list();
+            Response::Extension(ExtensionResponse::UpstreamListV2(UpstreamListV2 {
+                upstreams,
+            }))
+        }
+        Request::Extension(Extension::Stats) => {
+            tracing::info!("processing stats request");
+            Response::Extension(ExtensionResponse::Stats(Stats {
+                families: context.stats(),
+            }))
+        }
+        Request::Extension(Extension::Status) => {
+            tracing::info!("processing status request");
+            Response::Extension(ExtensionResponse::Status(context.status()))
+        }
+        Request::Extension(Extension::UpstreamDetails) => {
+            tracing::info!("processing upstream details request");
+            Response::Extension(ExtensionResponse::UpstreamDetails(
+                context.upstream_details(),
+            ))
+        }
+        Request::Extension(Extension::RemoveUpstream(path)) => {
+            tracing::info!(%path, "removing upstream");
+            if context.upstreams.remove(&path, "removed by user") {
+                Response::SUCCESS
+            } else {
+                Response::Extension(ExtensionResponse::Error(
+                    eyre!("no upstream {path:?}").into(),
+                ))
             }
-            Request::Extension(extension) => {
-                tracing::warn!(
-                    kind = extension.kind(),
-                    "received unsupported extension kind"
-                );
-                messages.send(Response::FAILURE).await?;
+        }
+        Request::Extension(Extension::SetPreferred(path)) => {
+            tracing::info!(%path, "setting preferred upstream");
+            if context.upstreams.set_preferred(&path) {
+                Response::SUCCESS
+            } else {
+                Response::Extension(ExtensionResponse::Error(
+                    eyre!("no upstream {path:?}").into(),
+                ))
             }
-            message => {
-                tracing::warn!(kind = message.kind(), "received unsupported message kind");
-                messages.send(Response::FAILURE).await?;
+        }
+        Request::Extension(Extension::LoopCheck(instance)) => {
+            tracing::info!("processing loop check");
+            if instance == context.instance {
+                Response::SUCCESS
+            } else {
+                Response::FAILURE
+            }
+        }
+        Request::Extension(Extension::Monitor) => {
+            tracing::info!("subscribing to events");
+            Response::SUCCESS
+        }
+        Request::Extension(Extension::SessionBind(ref bind)) => {
+            let destination = packets::fingerprint(&bind.host_key);
+            tracing::info!(destination, bind.forwarding, "processing session bind");
+            if let Err(error) = session.check_bind(bind) {
+                tracing::warn!(destination, "refusing session bind, {error}");
+                return Response::FAILURE;
             }
+            *session.destination.borrow_mut() = Some(destination);
+            *session.session_id.borrow_mut() = Some(bind.session_id.clone());
+            session.forwarding.set(bind.forwarding);
+            // Upstreams may also want to apply their own restrictions, so still pass it on
+            context.upstreams.forward_unknown(message).await?
+        }
+        Request::Extension(Extension::Unknown { ref kind, .. }) => {
+            tracing::info!(kind, "processing unknown extension");
+            context.upstreams.forward_unknown(message).await?
+        }
+        Request::Unknown { kind, .. } => {
+            tracing::info!(kind, "processing unknown message");
+            context.upstreams.forward_unknown(message).await?
         }
     }
+}
 
-    tracing::debug!("client connection closed");
+/// Records a sign request in the audit log once it's answered, or if it's dropped because the
+/// client disconnected first, as an upstream may already have been asked to sign
+struct SignAudit<'a> {
+    context: &'a Context,
+    session: &'a Session,
+    fingerprint: String,
+    flags: u32,
+    signing: Option<String>,
+    started: Instant,
+    /// The upstream that signed, or why the request failed
+    outcome: Option<Result<String, String>>,
+}
+
+impl SignAudit<'_> {
+    fn finish(mut self, outcome: Result<String, String>) {
+        self.outcome = Some(outcome);
+    }
+}
+
+impl Drop for SignAudit<'_> {
+    fn drop(&mut self) {
+        let (upstream, error) = match self.outcome.take() {
+            Some(Ok(upstream)) => (Some(upstream), None),
+            Some(Err(error)) => (None, Some(error)),
+            None => (None, Some("client disconnected".to_owned())),
+        };
+        self.context.audit.record(Event::Sign {
+            requester: Requester::from(&self.session.peer),
+            fingerprint: std::mem::take(&mut self.fingerprint),
+            flags: self.flags,
+            signing: self.signing.take(),
+            upstream,
+            error,
+            latency_ms: self.started.elapsed().as_secs_f64() * 1000.0,
+            destination: self.session.destination.borrow().clone(),
+        });
+    }
+}
+
+/// Resolves once the client has closed its socket entirely, unlike reading which ends once it has
+/// only shut down its write side
+async fn hung_up(socket: &UnixStream) {
+    loop {
+        match socket.ready(Interest::WRITABLE).await {
+            Ok(ready) if !ready.is_write_closed() => {
+                // Clear the readiness so we wait for the next change
+                let _ = socket.try_io(Interest::WRITABLE, || {
+                    Err::<(), _>(std::io::ErrorKind::WouldBlock.into())
+                });
+            }
+            _ => return,
+        }
+    }
+}
+
+/// Sends each event to a subscribed client as its own response, until the client disconnects or the
+/// daemon shuts down
+#[culpa::throws]
+async fn stream_events(
+    mut events: broadcast::Receiver<monitor::Event>,
+    mut requests: Pin<&mut impl Stream<Item = Result<Request, Error>>>,
+    mut responses: Pin<&mut impl Sink<Response, Error = Error>>,
+) {
+    loop {
+        let event = match future::select(pin!(events.recv()), requests.next()).await {
+            Either::Left((Ok(event), _)) => event,
+            Either::Left((Err(broadcast::error::RecvError::Lagged(count)), _)) => {
+                tracing::warn!(count, "monitor client fell behind, dropped events");
+                monitor::Event {
+                    time: SystemTime::now(),
+                    kind: EventKind::Missed { count },
+                }
+            }
+            Either::Left((Err(broadcast::error::RecvError::Closed), _)) => return,
+            // Subscribers only listen, so anything else they send ends the subscription too
+            Either::Right(_) => return,
+        };
+        responses
+            .send(Response::Extension(ExtensionResponse::Event(event)))
+            .await?;
+    }
+}
+
+/// Asks the user to confirm a sign request if the config requires it, returns whether it may be
+/// forwarded
+#[culpa::throws]
+async fn confirm_sign(
+    context: &Context,
+    session: &Session,
+    blob: &Bytes,
+    signing: Option<&SignData>,
+) -> bool {
+    let peer = &session.peer;
+    let config = context.config();
+    let fingerprint = packets::fingerprint(blob);
+    if !config.confirm.requires(&fingerprint, &[]) && !config.confirm.by_upstream() {
+        return true;
+    }
+
+    let (holders, comment) = context.upstreams.holders(blob).await;
+    if !config.confirm.requires(&fingerprint, &holders) {
+        return true;
+    }
+
+    let process = match (&peer.exe, peer.pid) {
+        (Some(exe), Some(pid)) => format!("{} (pid {pid})", exe.display()),
+        (None, Some(pid)) => format!("pid {pid}"),
+        _ => "an unknown process".to_owned(),
+    };
+    let mut prompt = format!(
+        "Allow {process} to sign with key {}?\nKey fingerprint {fingerprint}.",
+        comment
+            .as_deref()
+            .map(String::from_utf8_lossy)
+            .unwrap_or_default(),
+    );
+    if let Some(signing) = signing {
+        prompt += &format!("\nSigning: {signing}");
+    }
+    if let Some(host) = session.bound_host(&config) {
+        prompt += &format!("\nDestination: {host}");
+    } else if session.forwarding.get() {
+        let forwarded_to =
+            session.destination.borrow().clone().map(|destination| {
+                Host::lookup(destination, &config.destinations.known_hosts_files())
+            });
+        match forwarded_to {
+            Some(host) => prompt += &format!("\nForwarded: the agent is forwarded to {host}"),
+            None => prompt += "\nForwarded: the agent is forwarded to another host",
+        }
+    }
+    if let Some(cmdline) = &peer.cmdline {
+        prompt += &format!("\nCommand: {cmdline}");
+    }
+
+    let confirmed = askpass::confirm(&prompt).await?;
+    if confirmed {
+        tracing::info!(fingerprint, "sign request confirmed");
+    } else {
+        tracing::info!(fingerprint, "sign request declined");
+    }
+    confirmed
 }
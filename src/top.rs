@@ -0,0 +1,529 @@
+use crossterm::event::{Event as TermEvent, EventStream, KeyCode, KeyEventKind, KeyModifiers};
+use eyre::{bail, Error};
+use futures::{
+    future,
+    stream::{self, StreamExt as _},
+};
+use indexmap::IndexMap;
+use ratatui::{
+    layout::{Constraint, Layout},
+    style::{Color, Modifier, Style, Stylize as _},
+    text::{Line, Span},
+    widgets::{Block, List, ListState, Paragraph},
+    DefaultTerminal, Frame,
+};
+use secrecy::{ExposeSecret as _, SecretBytesMut, SecretString};
+use std::{
+    collections::VecDeque,
+    pin::pin,
+    rc::Rc,
+    time::{Duration, SystemTime},
+};
+use tokio_stream::wrappers::IntervalStream;
+
+use crate::{
+    app::Context,
+    client::Client,
+    monitor::{Event, EventKind},
+    packets::{self, DaemonStatus, UpstreamDetail, UpstreamDetails},
+};
+
+/// How often the upstreams are refreshed, and elapsed times are redrawn
+const REFRESH: Duration = Duration::from_secs(1);
+
+/// How many finished sign requests are kept for the history panel
+const HISTORY: usize = 100;
+
+enum Update {
+    Daemon(Result<Event, Error>),
+    Disconnected,
+    Terminal(std::io::Result<TermEvent>),
+    Tick,
+}
+
+enum Action {
+    Quit,
+    Remove(Rc<str>),
+    Prefer(Rc<str>),
+    Lock(SecretBytesMut),
+}
+
+/// A request the daemon hasn't answered yet
+struct InFlight {
+    started: SystemTime,
+    kind: String,
+    fingerprint: Option<String>,
+    /// The upstreams a sign request has been sent to that haven't answered yet
+    waiting_on: Vec<Rc<str>>,
+    signed_by: Option<Rc<str>>,
+}
+
+struct Signed {
+    time: SystemTime,
+    process: String,
+    fingerprint: String,
+    signed_by: Option<Rc<str>>,
+    elapsed: Duration,
+}
+
+/// The passphrase is entered twice, the same as `ssh-add -x`
+struct LockPrompt {
+    first: Option<SecretString>,
+    typed: SecretString,
+}
+
+impl LockPrompt {
+    /// Edits the typed passphrase into a new secret, growing a `String` in place would leave
+    /// unzeroed copies behind whenever it reallocates
+    fn edit(&mut self, edit: impl FnOnce(&mut String)) {
+        let mut typed = String::with_capacity(self.typed.expose_secret().len() + 4);
+        typed.push_str(self.typed.expose_secret());
+        edit(&mut typed);
+        self.typed = SecretString::new(typed);
+    }
+}
+
+#[derive(Default)]
+struct State {
+    status: Option<DaemonStatus>,
+    details: Option<UpstreamDetails>,
+    /// The process on each connection opened since we subscribed
+    processes: IndexMap<u64, String>,
+    in_flight: IndexMap<u64, InFlight>,
+    /// Newest first
+    history: VecDeque<Signed>,
+    selected: usize,
+    lock: Option<LockPrompt>,
+    message: Option<String>,
+}
+
+/// Shows live panels of what the daemon is doing until the user quits
+#[culpa::throws]
+pub(crate) async fn run(client: Client, context: &Context) {
+    let events = client.monitor().await?;
+    let mut terminal = ratatui::try_init()?;
+    let result = show(&mut terminal, &client, events, context).await;
+    ratatui::try_restore()?;
+    result?
+}
+
+#[culpa::throws]
+async fn show(
+    terminal: &mut DefaultTerminal,
+    client: &Client,
+    events: impl futures::Stream<Item = Result<Event, Error>>,
+    context: &Context,
+) {
+    let mut updates = pin!(stream::select(
+        stream::select(
+            events
+                .map(Update::Daemon)
+                .chain(stream::once(future::ready(Update::Disconnected))),
+            EventStream::new().map(Update::Terminal),
+        ),
+        IntervalStream::new(tokio::time::interval(REFRESH)).map(|_| Update::Tick),
+    )
+    .take_until(context.shutdown.clone()));
+
+    let mut state = State::default();
+    while let Some(update) = updates.next().await {
+        match update {
+            Update::Daemon(event) => {
+                if state.apply(event?) {
+                    state.refresh(client).await;
+                }
+            }
+            Update::Disconnected => bail!("the daemon closed the connection"),
+            Update::Terminal(event) => {
+                let action = match event? {
+                    TermEvent::Key(key) if key.kind == KeyEventKind::Press => state.key(key),
+                    _ => None,
+                };
+                // Only say what was done once the daemon has done it
+                let result = match action {
+                    None => Ok(None),
+                    Some(Action::Quit) => return,
+                    Some(Action::Remove(path)) => client
+                        .remove_upstream(path.clone())
+                        .await
+                        .map(|()| Some(format!("removed {path}"))),
+                    Some(Action::Prefer(path)) => client
+                        .set_preferred(path.clone())
+                        .await
+                        .map(|()| Some(format!("preferring {path}"))),
+                    Some(Action::Lock(passphrase)) => client
+                        .lock(passphrase)
+                        .await
+                        .map(|()| Some("locked all upstreams".to_owned())),
+                };
+                match result {
+                    Ok(Some(message)) => state.message = Some(message),
+                    Ok(None) => {}
+                    Err(e) => state.message = Some(format!("failed: {e:#}")),
+                }
+                state.refresh(client).await;
+            }
+            Update::Tick => state.refresh(client).await,
+        }
+        terminal.draw(|frame| state.draw(frame))?;
+    }
+}
+
+impl State {
+    async fn refresh(&mut self, client: &Client) {
+        match future::try_join(client.status(), client.upstream_details()).await {
+            Ok((status, details)) => {
+                self.selected = self.selected.min(details.upstreams.len().saturating_sub(1));
+                self.status = Some(status);
+                self.details = Some(details);
+            }
+            Err(e) => self.message = Some(format!("failed to refresh: {e:#}")),
+        }
+    }
+
+    /// Returns whether the upstreams changed
+    fn apply(&mut self, event: Event) -> bool {
+        match event.kind {
+            EventKind::ConnectionOpened {
+                connection,
+                pid,
+                exe,
+                ..
+            } => {
+                let process = match (exe, pid) {
+                    (Some(exe), Some(pid)) => format!("{exe} ({pid})"),
+                    (Some(exe), None) => exe,
+                    (None, Some(pid)) => format!("pid {pid}"),
+                    (None, None) => "unknown process".to_owned(),
+                };
+                self.processes.insert(connection, process);
+            }
+            EventKind::ConnectionClosed { connection } => {
+                self.processes.shift_remove(&connection);
+                self.in_flight.shift_remove(&connection);
+            }
+            // Our own refreshes would flicker through the panel, so management extensions aren't
+            // shown
+            EventKind::Request { kind, .. } if kind.ends_with("@nemo157.com") => {}
+            EventKind::Request {
+                connection,
+                kind,
+                fingerprint,
+            } => {
+                self.in_flight.insert(
+                    connection,
+                    InFlight {
+                        started: event.time,
+                        kind,
+                        fingerprint,
+                        waiting_on: Vec::new(),
+                        signed_by: None,
+                    },
+                );
+            }
+            EventKind::Responded { connection, .. } => {
+                let Some(request) = self.in_flight.shift_remove(&connection) else {
+                    return false;
+                };
+                if let Some(fingerprint) = request
+                    .fingerprint
+                    .filter(|_| request.kind == "sign_request")
+                {
+                    self.history.push_front(Signed {
+                        time: event.time,
+                        process: self.process(connection),
+                        fingerprint,
+                        signed_by: request.signed_by,
+                        elapsed: event
+                            .time
+                            .duration_since(request.started)
+                            .unwrap_or_default(),
+                    });
+                    self.history.truncate(HISTORY);
+                }
+            }
+            EventKind::SignSent {
+                upstream,
+                fingerprint,
+            } => {
+                for request in self.signing(&fingerprint) {
+                    if !request.waiting_on.contains(&upstream) {
+                        request.waiting_on.push(upstream.clone());
+                    }
+                }
+            }
+            EventKind::SignAnswered {
+                upstream,
+                fingerprint,
+                outcome,
+                ..
+            } => {
+                for request in self.signing(&fingerprint) {
+                    request.waiting_on.retain(|path| *path != upstream);
+                    if outcome == "success" && request.signed_by.is_none() {
+                        request.signed_by = Some(upstream.clone());
+                    }
+                }
+            }
+            EventKind::UpstreamAdded { .. } | EventKind::UpstreamRemoved { .. } => return true,
+            EventKind::Timeout { upstream } => {
+                self.message = Some(format!("request to {upstream} timed out"));
+            }
+            EventKind::Missed { count } => {
+                self.message = Some(format!("fell behind, missed {count} events"));
+            }
+        }
+        false
+    }
+
+    /// The in-flight sign requests for this key, we can't tell which connection an upstream sign
+    /// request is for so they all share the same upstreams
+    fn signing<'a>(&'a mut self, fingerprint: &'a str) -> impl Iterator<Item = &'a mut InFlight> {
+        self.in_flight.values_mut().filter(move |request| {
+            request.kind == "sign_request" && request.fingerprint.as_deref() == Some(fingerprint)
+        })
+    }
+
+    fn process(&self, connection: u64) -> String {
+        self.processes
+            .get(&connection)
+            .cloned()
+            .unwrap_or_else(|| format!("connection {connection}"))
+    }
+
+    fn selected_path(&self) -> Option<Rc<str>> {
+        let details = self.details.as_ref()?;
+        Some(details.upstreams.get(self.selected)?.path.clone())
+    }
+
+    fn key(&mut self, key: crossterm::event::KeyEvent) -> Option<Action> {
+        if key.code == KeyCode::Char('c') && key.modifiers.contains(KeyModifiers::CONTROL) {
+            return Some(Action::Quit);
+        }
+
+        if let Some(prompt) = &mut self.lock {
+            match key.code {
+                KeyCode::Char(c) => prompt.edit(|typed| typed.push(c)),
+                KeyCode::Backspace => prompt.edit(|typed| {
+                    typed.pop();
+                }),
+                KeyCode::Esc => self.lock = None,
+                KeyCode::Enter => match prompt.first.take() {
+                    None => {
+                        prompt.first = Some(std::mem::replace(
+                            &mut prompt.typed,
+                            SecretString::new(String::new()),
+                        ))
+                    }
+                    Some(first) => {
+                        let matches = first.expose_secret() == prompt.typed.expose_secret();
+                        self.lock = None;
+                        if !matches {
+                            self.message = Some("passphrases didn't match".to_owned());
+                            return None;
+                        }
+                        return Some(Action::Lock(SecretBytesMut::new(
+                            first.expose_secret().as_bytes(),
+                        )));
+                    }
+                },
+                _ => {}
+            }
+            return None;
+        }
+
+        self.message = None;
+        match key.code {
+            KeyCode::Char('q') | KeyCode::Esc => return Some(Action::Quit),
+            KeyCode::Up | KeyCode::Char('k') => self.selected = self.selected.saturating_sub(1),
+            KeyCode::Down | KeyCode::Char('j') => {
+                let upstreams = self.details.as_ref().map_or(0, |d| d.upstreams.len());
+                self.selected = (self.selected + 1).min(upstreams.saturating_sub(1));
+            }
+            KeyCode::Char('d') | KeyCode::Delete => {
+                return self.selected_path().map(Action::Remove)
+            }
+            KeyCode::Char('p') => return self.selected_path().map(Action::Prefer),
+            KeyCode::Char('l') => {
+                self.lock = Some(LockPrompt {
+                    first: None,
+                    typed: SecretString::new(String::new()),
+                })
+            }
+            _ => {}
+        }
+        None
+    }
+
+    fn draw(&self, frame: &mut Frame<'_>) {
+        let [header, top, in_flight, history, footer] = Layout::vertical([
+            Constraint::Length(1),
+            Constraint::Percentage(40),
+            Constraint::Percentage(25),
+            Constraint::Fill(1),
+            Constraint::Length(1),
+        ])
+        .areas(frame.area());
+        let [upstreams, identities] =
+            Layout::horizontal([Constraint::Percentage(50), Constraint::Percentage(50)]).areas(top);
+        let now = SystemTime::now();
+        let seconds = |duration: Duration| format!("{:.1}s", duration.as_secs_f64());
+
+        let header_line = match &self.status {
+            Some(status) => Line::from(vec![
+                Span::raw(format!(
+                    "sshagmux {}, up {}, {} connections, ",
+                    status.version,
+                    humantime::format_duration(Duration::from_secs(status.uptime.as_secs())),
+                    status.connections,
+                )),
+                if status.locked {
+                    "locked".red().bold()
+                } else {
+                    "unlocked".green()
+                },
+            ]),
+            None => Line::raw("connecting..."),
+        };
+        frame.render_widget(Paragraph::new(header_line), header);
+
+        let preferred = self.details.as_ref().and_then(|d| d.preferred.clone());
+        let rows = self
+            .status
+            .iter()
+            .flat_map(|status| &status.upstreams)
+            .map(|upstream| {
+                let latency = self
+                    .details
+                    .iter()
+                    .flat_map(|details| &details.upstreams)
+                    .find(|detail| detail.path == upstream.path)
+                    .and_then(|detail| detail.latency);
+                let mut spans = vec![
+                    Span::raw(if preferred.as_ref() == Some(&upstream.path) {
+                        "* "
+                    } else {
+                        "  "
+                    }),
+                    Span::raw(upstream.path.to_string()),
+                    Span::raw(" "),
+                    if upstream.healthy {
+                        "healthy".green()
+                    } else {
+                        "unhealthy".red()
+                    },
+                ];
+                if let Some(latency) = latency {
+                    spans.push(Span::raw(format!(" {}ms", latency.as_millis())));
+                }
+                if upstream.forward_adds {
+                    spans.push(" adds".dim());
+                }
+                Line::from(spans)
+            });
+        let mut list_state = ListState::default().with_selected(Some(self.selected));
+        frame.render_stateful_widget(
+            List::new(rows)
+                .block(Block::bordered().title("Upstreams (* preferred)"))
+                .highlight_style(Style::new().add_modifier(Modifier::REVERSED)),
+            upstreams,
+            &mut list_state,
+        );
+
+        let selected = self
+            .details
+            .as_ref()
+            .and_then(|details| details.upstreams.get(self.selected));
+        let keys: Vec<Line<'_>> = match selected {
+            Some(UpstreamDetail {
+                identities: Some(identities),
+                ..
+            }) if !identities.is_empty() => identities
+                .iter()
+                .map(|key| {
+                    Line::from(vec![
+                        Span::raw(packets::key_type(&key.blob).unwrap_or_default()),
+                        Span::raw(" "),
+                        Span::raw(key.fingerprint()).fg(Color::Cyan),
+                        Span::raw(" "),
+                        Span::raw(key.comment().into_owned()),
+                    ])
+                })
+                .collect(),
+            Some(UpstreamDetail {
+                identities: Some(_),
+                ..
+            }) => vec![Line::raw("no identities").dim()],
+            Some(_) => vec![Line::raw("no identities cached yet").dim()],
+            None => Vec::new(),
+        };
+        frame.render_widget(
+            List::new(keys).block(Block::bordered().title(match selected {
+                Some(detail) => format!("Identities from {}", detail.path),
+                None => "Identities".to_owned(),
+            })),
+            identities,
+        );
+
+        let requests = self.in_flight.iter().map(|(&connection, request)| {
+            let elapsed = now.duration_since(request.started).unwrap_or_default();
+            let mut line = format!(
+                "{:>7} {} {}",
+                seconds(elapsed),
+                self.process(connection),
+                request.kind,
+            );
+            if let Some(fingerprint) = &request.fingerprint {
+                line += &format!(" {fingerprint}");
+            }
+            if !request.waiting_on.is_empty() {
+                let waiting_on: Vec<_> = request.waiting_on.iter().map(|p| p.as_ref()).collect();
+                line += &format!(", waiting on {}", waiting_on.join(", "));
+            }
+            Line::raw(line)
+        });
+        frame.render_widget(
+            List::new(requests).block(Block::bordered().title("In flight")),
+            in_flight,
+        );
+
+        let signs = self.history.iter().map(|signed| {
+            let outcome = match &signed.signed_by {
+                Some(upstream) => Span::raw(format!("signed by {upstream}")).green(),
+                None => "refused".red(),
+            };
+            Line::from(vec![
+                Span::raw(format!(
+                    "{} {} {} ",
+                    humantime::format_rfc3339_seconds(signed.time),
+                    signed.process,
+                    signed.fingerprint,
+                )),
+                outcome,
+                Span::raw(format!(" in {}", seconds(signed.elapsed))),
+            ])
+        });
+        frame.render_widget(
+            List::new(signs).block(Block::bordered().title("Recent signs")),
+            history,
+        );
+
+        let footer_line = match (&self.lock, &self.message) {
+            (Some(prompt), _) => Line::raw(format!(
+                "{} passphrase (esc to cancel): {}",
+                if prompt.first.is_some() {
+                    "Confirm lock"
+                } else {
+                    "Lock"
+                },
+                "*".repeat(prompt.typed.expose_secret().chars().count()),
+            )),
+            (None, Some(message)) => Line::raw(message.as_str()).bold(),
+            (None, None) => Line::raw(
+                "↑/↓ select upstream   d remove it   p prefer it   l lock the daemon   q quit",
+            )
+            .dim(),
+        };
+        frame.render_widget(Paragraph::new(footer_line), footer);
+    }
+}
@@ -5,73 +5,355 @@ use futures::{
     stream::{self, FuturesOrdered, Stream, StreamExt},
 };
 use indexmap::{IndexMap, IndexSet};
-use std::{cell::RefCell, future::Future, pin::pin, rc::Rc, time::Duration};
+use std::{
+    cell::RefCell,
+    future::Future,
+    pin::pin,
+    rc::Rc,
+    time::{Duration, Instant},
+};
 
 use crate::{
+    audit::{AuditLog, Event},
+    capture::Capture,
     client::Client,
-    packets::{PublicKey, Request, Response},
+    config::{Config, Passthrough, Routing, Target, TimeoutOverrides},
+    keystore::{KeyStore, KEYSTORE_PATH},
+    metrics::Metrics,
+    monitor::{EventKind, Events},
+    net::SocketReplaced,
+    packets::{self, Extension, PublicKey, Request, Response},
+    verify,
 };
 
 #[derive(Debug, Clone)]
 pub(crate) struct Upstream {
     pub(crate) path: Rc<str>,
     pub(crate) forward_adds: bool,
+    /// Only sent when adding an upstream, not included when listing them
+    pub(crate) timeouts: TimeoutOverrides,
+}
+
+/// What we've learnt about an upstream from the requests sent to it
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Health {
+    pub(crate) last_success: Option<Instant>,
+    pub(crate) last_failure: Option<(Instant, Rc<str>)>,
+    /// The identities returned by the last successful identities request
+    pub(crate) identities: Option<(Instant, Rc<[PublicKey]>)>,
+    /// How long that identities request took, sign requests aren't timed as they may be waiting
+    /// on a human
+    pub(crate) latency: Option<Duration>,
+    /// Whether a lock request passed through to it succeeded, without an unlock since
+    pub(crate) locked: bool,
+}
+
+#[derive(Debug)]
+struct Entry {
+    client: Rc<Client>,
+    health: Health,
 }
 
 pub(crate) struct Upstreams {
-    #[allow(clippy::type_complexity)]
-    clients: Rc<RefCell<IndexMap<Rc<str>, Rc<Client>>>>,
+    clients: Rc<RefCell<IndexMap<Rc<str>, Entry>>>,
+    preferred: RefCell<Option<Rc<str>>>,
+    routing: RefCell<Rc<Routing>>,
+    audit: Rc<AuditLog>,
+    metrics: Rc<Metrics>,
+    events: Rc<Events>,
+    capture: Rc<Capture>,
+}
+
+impl Health {
+    /// Whether the last request to this upstream succeeded
+    pub(crate) fn is_healthy(&self) -> bool {
+        match (self.last_success, &self.last_failure) {
+            (_, None) => true,
+            (Some(success), Some((failure, _))) => success > *failure,
+            (None, Some(_)) => false,
+        }
+    }
 }
 
 impl Upstreams {
-    pub(crate) fn new() -> Self {
+    pub(crate) fn new(
+        audit: Rc<AuditLog>,
+        metrics: Rc<Metrics>,
+        events: Rc<Events>,
+        capture: Rc<Capture>,
+    ) -> Self {
         Self {
             clients: Rc::new(RefCell::new(IndexMap::new())),
+            preferred: RefCell::new(None),
+            routing: RefCell::new(Rc::new(Routing::default())),
+            audit,
+            metrics,
+            events,
+            capture,
         }
     }
 
     pub(crate) async fn add(&self, client: Client) {
-        // We explicitly remove and readd the client to put it at the end of the list
         let mut clients = self.clients.borrow_mut();
+        // Re-adding a static upstream keeps it static, with the settings from the config
+        let client = match clients.get(&client.path) {
+            Some(entry) if entry.client.persistent => Client {
+                forward_adds: entry.client.forward_adds,
+                persistent: true,
+                timeout_overrides: entry.client.timeout_overrides,
+                timeouts: entry.client.timeouts,
+                ..client
+            },
+            _ => client,
+        };
+        let client = Client {
+            capture: Some(self.capture.clone()),
+            ..client
+        };
+        self.added(&client);
+        // We explicitly remove and readd the client to put it at the end of the list
         clients.shift_remove(&client.path);
-        clients.insert(client.path.clone(), Rc::new(client));
+        clients.insert(
+            client.path.clone(),
+            Entry {
+                client: Rc::new(client),
+                health: Health::default(),
+            },
+        );
+    }
+
+    /// Returns whether there was an upstream with this path to remove
+    pub(crate) fn remove(&self, path: &str, reason: &str) -> bool {
+        let removed = self.clients.borrow_mut().shift_remove(path).is_some();
+        if removed {
+            self.removed(path, reason);
+        }
+        removed
+    }
+
+    fn added(&self, client: &Client) {
+        self.audit.record(Event::UpstreamAdded {
+            path: client.path.to_string(),
+            forward_adds: client.forward_adds,
+        });
+        self.events.emit(EventKind::UpstreamAdded {
+            path: client.path.clone(),
+        });
+    }
+
+    fn removed(&self, path: &str, reason: &str) {
+        self.metrics.upstream_removed(reason);
+        self.audit.record(Event::UpstreamRemoved {
+            path: path.to_owned(),
+            reason: reason.to_owned(),
+        });
+        self.events.emit(EventKind::UpstreamRemoved {
+            path: Rc::from(path),
+            reason: reason.to_owned(),
+        });
+    }
+
+    /// Applies the routing and timeouts from `config` to all upstreams, and updates the static
+    /// upstreams to match it
+    pub(crate) fn configure(&self, config: &Config) {
+        *self.preferred.borrow_mut() = config.preferred();
+        *self.routing.borrow_mut() = Rc::new(config.routing.clone());
+
+        let mut clients = self.clients.borrow_mut();
+        clients.retain(|path, entry| {
+            let keep = !entry.client.persistent
+                || (entry.client.keystore.is_some() && config.keystore.enable)
+                || config
+                    .upstreams
+                    .iter()
+                    .any(|upstream| upstream.path == path.as_ref());
+            if !keep && entry.client.keystore.is_some() {
+                tracing::info!("disabled keystore, its identities have been dropped");
+                self.removed(path, "keystore disabled");
+            } else if !keep {
+                tracing::info!(%path, "removed static upstream");
+                self.removed(path, "removed from config");
+            }
+            keep
+        });
+
+        for entry in clients.values_mut() {
+            entry.client = Rc::new(Client {
+                timeouts: entry.client.timeout_overrides.apply(config.timeouts),
+                ..Client::clone(&entry.client)
+            });
+        }
+
+        for upstream in &config.upstreams {
+            let client = Client {
+                path: Rc::from(upstream.path.as_str()),
+                forward_adds: upstream.forward_adds,
+                persistent: true,
+                timeouts: upstream.timeouts.apply(config.timeouts),
+                timeout_overrides: upstream.timeouts,
+                keystore: None,
+                socket: None,
+                capture: Some(self.capture.clone()),
+            };
+            match clients.get_mut(upstream.path.as_str()) {
+                Some(entry) => {
+                    entry.client = Rc::new(client);
+                }
+                None => {
+                    tracing::info!(%upstream.path, upstream.forward_adds, "added static upstream");
+                    self.added(&client);
+                    clients.insert(
+                        client.path.clone(),
+                        Entry {
+                            client: Rc::new(client),
+                            health: Health::default(),
+                        },
+                    );
+                }
+            }
+        }
+
+        if config.keystore.enable && !clients.contains_key(KEYSTORE_PATH) {
+            tracing::info!("enabled keystore");
+            let client = Client::keystore(Rc::new(KeyStore::default()), config.timeouts);
+            self.added(&client);
+            clients.insert(
+                client.path.clone(),
+                Entry {
+                    client: Rc::new(client),
+                    health: Health::default(),
+                },
+            );
+        }
     }
 
     pub(crate) fn list(&self) -> Vec<Upstream> {
         self.clients
             .borrow()
             .values()
-            .map(|client| client.info())
+            .map(|entry| entry.client.info())
+            .collect()
+    }
+
+    /// All upstreams along with their current health, in the same order as `list`
+    pub(crate) fn health(&self) -> Vec<(Rc<Client>, Health)> {
+        self.clients
+            .borrow()
+            .values()
+            .map(|entry| (entry.client.clone(), entry.health.clone()))
             .collect()
     }
 
+    pub(crate) fn preferred(&self) -> Option<Rc<str>> {
+        self.preferred.borrow().clone()
+    }
+
+    /// Tries this upstream first until the config is next reloaded, returns whether there is an
+    /// upstream with this path
+    pub(crate) fn set_preferred(&self, path: &str) -> bool {
+        let Some(path) = self
+            .clients
+            .borrow()
+            .get_key_value(path)
+            .map(|(path, _)| path.clone())
+        else {
+            return false;
+        };
+        *self.preferred.borrow_mut() = Some(path);
+        true
+    }
+
+    fn routing(&self) -> Rc<Routing> {
+        self.routing.borrow().clone()
+    }
+
+    fn record<T>(&self, path: &str, result: &Result<T, Error>) {
+        if let Some(entry) = self.clients.borrow_mut().get_mut(path) {
+            match result {
+                Ok(_) => entry.health.last_success = Some(Instant::now()),
+                Err(e) => {
+                    entry.health.last_failure = Some((Instant::now(), Rc::from(e.to_string())));
+                    if e.is::<tokio::time::error::Elapsed>() {
+                        self.metrics.timeout(&entry.client.path);
+                        self.events.emit(EventKind::Timeout {
+                            upstream: entry.client.path.clone(),
+                        });
+                    }
+                }
+            }
+        }
+    }
+
+    /// The clients in the order they should be tried, the preferred upstream followed by the most
+    /// recently added, with the keystore always last
+    fn ordered(&self) -> Vec<Rc<Client>> {
+        let clients = self.clients.borrow();
+        let preferred = self.preferred.borrow();
+        let preferred = preferred.as_ref();
+        let mut ordered: Vec<_> = preferred
+            .and_then(|path| clients.get(path))
+            .into_iter()
+            .chain(
+                clients
+                    .values()
+                    .rev()
+                    .filter(|entry| Some(&entry.client.path) != preferred),
+            )
+            .map(|entry| entry.client.clone())
+            .collect();
+        ordered.sort_by_key(|client| client.keystore.is_some());
+        ordered
+    }
+
     pub(crate) fn for_each_client<'a, F, R>(
         &'a self,
         f: impl Fn(Rc<Client>) -> F + 'a,
     ) -> impl Stream<Item = R> + 'a
+    where
+        F: Future<Output = Result<R, Error>>,
+    {
+        self.for_each_matching(|_| true, f)
+    }
+
+    /// Same as `for_each_client`, skipping the clients that don't match `filter`
+    fn for_each_matching<'a, F, R>(
+        &'a self,
+        filter: impl Fn(&Client) -> bool + 'a,
+        f: impl Fn(Rc<Client>) -> F + 'a,
+    ) -> impl Stream<Item = R> + 'a
     where
         F: Future<Output = Result<R, Error>>,
     {
         let f = Rc::new(f);
         async move {
-            self.clients
-                .borrow()
-                .values()
-                .rev()
+            self.ordered()
+                .into_iter()
+                .filter(|client| filter(client))
                 .map(|client| {
-                    let client = client.clone();
                     let clients = self.clients.clone();
                     let f = f.clone();
                     async move {
-                        match f(client.clone()).await {
+                        let result = f(client.clone()).await;
+                        self.record(&client.path, &result);
+                        match result {
                             Ok(result) => stream::iter(Some(result)),
                             Err(e) => {
                                 match e.downcast_ref::<std::io::Error>() {
-                                    Some(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                                    Some(e)
+                                        if e.kind() == std::io::ErrorKind::NotFound
+                                            && !client.persistent =>
+                                    {
                                         // Remove upstreams that have closed their socket,
                                         // other errors may be transient
                                         clients.borrow_mut().shift_remove(&client.path);
                                         tracing::warn!(path = %client.path, "removed dead upstream");
+                                        self.removed(&client.path, "socket closed");
+                                    }
+                                    _ if e.is::<SocketReplaced>() => {
+                                        // Whatever is listening now isn't what was verified
+                                        clients.borrow_mut().shift_remove(&client.path);
+                                        tracing::warn!(path = %client.path, "removed replaced upstream");
+                                        self.removed(&client.path, "socket replaced");
                                     }
                                     _ => {
                                         tracing::warn!("error returned from upstream: {e:?}");
@@ -88,39 +370,375 @@ impl Upstreams {
         .flatten_stream()
     }
 
+    /// Requests identities from every upstream, caching each upstream's response
+    fn identities_by_client(&self) -> impl Stream<Item = (Rc<Client>, Vec<PublicKey>)> + '_ {
+        self.for_each_client(|client| async move {
+            let started = Instant::now();
+            let keys = client.request_identities().await?;
+            Ok((client, keys, started.elapsed()))
+        })
+        .map(|(client, keys, latency)| {
+            if let Some(entry) = self.clients.borrow_mut().get_mut(&client.path) {
+                entry.health.identities = Some((Instant::now(), Rc::from(keys.as_slice())));
+                entry.health.latency = Some(latency);
+            }
+            (client, keys)
+        })
+    }
+
+    /// Only includes the keys for which `keep` returns true, given the upstream that holds them,
+    /// certificates are moved before the keys they certify
     #[culpa::throws]
-    pub(crate) async fn request_identities(&self) -> Vec<PublicKey> {
-        self.for_each_client(|client| async move { client.request_identities().await })
-            .flat_map(stream::iter)
+    pub(crate) async fn request_identities(
+        &self,
+        keep: impl Fn(&str, &PublicKey) -> bool,
+    ) -> Vec<PublicKey> {
+        let started = Instant::now();
+        let keep = &keep;
+        let keys = self
+            .identities_by_client()
+            .flat_map(|(client, keys)| {
+                stream::iter(keys.into_iter().filter(move |key| keep(&client.path, key)))
+            })
             .collect::<IndexSet<_>>()
-            .await
-            .into_iter()
-            .collect()
+            .await;
+        self.metrics.identities_latency(started.elapsed());
+        packets::certificates_first(keys.into_iter().collect())
     }
 
+    /// The upstreams whose cached identities include `blob`, along with its comment, refreshing
+    /// the cache first if none do
+    pub(crate) async fn holders(&self, blob: &Bytes) -> (Vec<Rc<str>>, Option<Bytes>) {
+        let cached = || {
+            let mut comment = None;
+            let holders =
+                self.clients
+                    .borrow()
+                    .values()
+                    .filter(|entry| {
+                        let key = entry.health.identities.as_ref().and_then(|(_, keys)| {
+                            keys.iter().find(|key| key.blob == blob).cloned()
+                        });
+                        if let Some(key) = &key {
+                            comment.get_or_insert_with(|| key.comment.clone());
+                        }
+                        key.is_some()
+                    })
+                    .map(|entry| entry.client.path.clone())
+                    .collect::<Vec<_>>();
+            (holders, comment)
+        };
+
+        let (holders, comment) = cached();
+        if !holders.is_empty() {
+            return (holders, comment);
+        }
+        self.identities_by_client()
+            .for_each(|_| future::ready(()))
+            .await;
+        cached()
+    }
+
+    /// The upstreams that requests routed to `target` should be sent to
+    fn targets(&self, target: &Target) -> Vec<Rc<Client>> {
+        match target {
+            Target::Latest => self
+                .ordered()
+                .into_iter()
+                .find(|client| client.forward_adds)
+                .into_iter()
+                .collect(),
+            Target::Broadcast => self
+                .ordered()
+                .into_iter()
+                .filter(|client| client.forward_adds)
+                .collect(),
+            Target::KeyStore => self
+                .clients
+                .borrow()
+                .get(KEYSTORE_PATH)
+                .map(|entry| entry.client.clone())
+                .into_iter()
+                .collect(),
+            Target::Upstream(path) => self
+                .clients
+                .borrow()
+                .get(path.as_str())
+                .map(|entry| entry.client.clone())
+                .into_iter()
+                .collect(),
+        }
+    }
+
+    /// Forwards an add identity request to the upstreams chosen by the routing rules
     #[culpa::throws]
-    pub(crate) async fn forward_to_adds(&self, message: Request) -> Response {
-        let Some(client) = self
-            .clients
-            .borrow()
-            .values()
-            .rev()
-            .find(|client| client.forward_adds)
-            .cloned()
-        else {
-            bail!("no client configured to forward adds to")
+    pub(crate) async fn forward_add(&self, message: Request) -> Response {
+        let routing = self.routing();
+        let (key_type, constrained) = match &message {
+            Request::AddIdentity { key_type, .. } => (key_type.as_str(), false),
+            Request::AddIdConstrained { key_type, .. } => (key_type.as_str(), true),
+            _ => bail!("not an add identity request"),
         };
-        client.send(message, Duration::from_secs(1)).await?
+        let target = routing
+            .add_rules
+            .iter()
+            .find(|rule| rule.matches(key_type, constrained))
+            .map_or(&routing.adds, |rule| &rule.to);
+        let clients = self.targets(target);
+        if clients.is_empty() {
+            bail!("no client configured to forward adds to ({target:?})")
+        }
+        self.send_to_all(clients, message).await?
     }
 
-    /// Returns a signature if any upstream gives a success
-    pub(crate) async fn sign_request(&self, blob: Bytes, data: Bytes, flags: u32) -> Option<Bytes> {
-        pin!(self
-            .for_each_client(|client| {
-                let blob = blob.clone();
-                let data = data.clone();
-                async move { client.sign_request(blob, data, flags).await }
+    /// Forwards a remove identity request to every upstream that currently holds the identity
+    #[culpa::throws]
+    pub(crate) async fn remove_identity(&self, blob: Bytes) -> Response {
+        let clients = self
+            .identities_by_client()
+            .filter_map(|(client, keys)| {
+                future::ready(keys.iter().any(|key| key.blob == blob).then_some(client))
+            })
+            .collect::<Vec<_>>()
+            .await;
+        if clients.is_empty() {
+            tracing::info!("no upstream holds the identity to remove");
+            return Response::FAILURE;
+        }
+        self.send_to_all(clients, Request::RemoveIdentity { blob })
+            .await?
+    }
+
+    /// Forwards a remove all identities request to the upstreams chosen by the routing rules
+    #[culpa::throws]
+    pub(crate) async fn remove_all_identities(&self) -> Response {
+        let target = &self.routing().remove_all;
+        let clients = self.targets(target);
+        if clients.is_empty() {
+            bail!("no client configured to forward adds to ({target:?})")
+        }
+        self.send_to_all(clients, Request::RemoveAllIdentities)
+            .await?
+    }
+
+    /// Locks or unlocks every upstream with the same passphrase, it only succeeds if they all do
+    /// so that nothing can sign while the daemon reports being locked. A lock that only some
+    /// upstreams accepted is undone, so that it can be tried again.
+    pub(crate) async fn lock(&self, message: Request) -> Response {
+        let clients = self.ordered();
+        if clients.is_empty() {
+            tracing::warn!("no upstreams to lock");
+            return Response::FAILURE;
+        }
+        let (succeeded, all) = self.send_lock(clients, message.clone()).await;
+        if let (Request::Lock { passphrase }, false) = (message, all) {
+            if !succeeded.is_empty() {
+                tracing::warn!("not every upstream locked, unlocking the others again");
+                self.send_lock(succeeded, Request::Unlock { passphrase })
+                    .await;
+            }
+        }
+        if all {
+            Response::SUCCESS
+        } else {
+            Response::FAILURE
+        }
+    }
+
+    /// Sends a lock or unlock request to all `clients` concurrently, recording which are now
+    /// locked, returns those that succeeded and whether they all did
+    async fn send_lock(
+        &self,
+        clients: Vec<Rc<Client>>,
+        message: Request,
+    ) -> (Vec<Rc<Client>>, bool) {
+        let locking = matches!(message, Request::Lock { .. });
+        let mut succeeded = Vec::new();
+        let mut all = true;
+        for (client, result) in self.send_to_each(clients, message).await {
+            match result {
+                Ok(Response::Success { .. }) => {
+                    if let Some(entry) = self.clients.borrow_mut().get_mut(&client.path) {
+                        entry.health.locked = locking;
+                    }
+                    succeeded.push(client);
+                }
+                Ok(response) => {
+                    tracing::warn!(path = %client.path, kind = response.kind(), locking, "upstream did not succeed");
+                    all = false;
+                }
+                Err(e) => {
+                    tracing::warn!(path = %client.path, locking, "error returned from upstream: {e:?}");
+                    all = false;
+                }
+            }
+        }
+        (succeeded, all)
+    }
+
+    /// Whether every upstream was locked through the daemon and hasn't been unlocked since
+    pub(crate) fn is_locked(&self) -> bool {
+        let clients = self.clients.borrow();
+        !clients.is_empty() && clients.values().all(|entry| entry.health.locked)
+    }
+
+    /// Passes a request we don't understand through to the upstreams chosen by the routing rules,
+    /// relaying the first response that isn't a failure
+    #[culpa::throws]
+    pub(crate) async fn forward_unknown(&self, message: Request) -> Response {
+        let routing = self.routing();
+        let clients = match &routing.unknown {
+            // `ssh` sends a session bind every time it connects, it's fine to refuse it
+            Passthrough::Refuse
+                if matches!(message, Request::Extension(Extension::SessionBind(_))) =>
+            {
+                tracing::debug!("not passing on session bind, routing.unknown is not configured");
+                return Response::FAILURE;
+            }
+            Passthrough::Refuse => {
+                tracing::warn!("refusing unsupported request, routing.unknown is not configured");
+                return Response::FAILURE;
+            }
+            Passthrough::Preferred => self.ordered().into_iter().take(1).collect(),
+            Passthrough::Adds => self.targets(&routing.adds),
+            Passthrough::FanOut => self.ordered(),
+            Passthrough::Upstream(path) => self.targets(&Target::Upstream(path.clone())),
+        };
+
+        for client in clients {
+            // We don't know what the request does, so allow it as long as a sign request in case it
+            // needs human interaction
+            let result = client.send(message.clone(), client.timeouts.sign).await;
+            self.record(&client.path, &result);
+            match result {
+                Ok(Response::Failure { .. }) => {
+                    tracing::debug!(path = %client.path, "upstream refused passed through request");
+                }
+                Ok(response) => {
+                    tracing::info!(path = %client.path, "relaying passed through response");
+                    return response;
+                }
+                Err(e) => {
+                    tracing::warn!(path = %client.path, "error returned from upstream: {e:?}");
+                }
+            }
+        }
+
+        Response::FAILURE
+    }
+
+    /// Sends `message` to all `clients` concurrently, a single client's response is returned
+    /// as-is, otherwise it is only a success if every client succeeded
+    #[culpa::throws]
+    async fn send_to_all(&self, clients: Vec<Rc<Client>>, message: Request) -> Response {
+        if let [client] = clients.as_slice() {
+            let result = client.send(message, client.timeouts.management).await;
+            self.record(&client.path, &result);
+            return result?;
+        }
+
+        let mut success = true;
+        for (client, result) in self.send_to_each(clients, message).await {
+            match result {
+                Ok(Response::Success { .. }) => {}
+                Ok(response) => {
+                    tracing::warn!(path = %client.path, kind = response.kind(), "upstream did not succeed");
+                    success = false;
+                }
+                Err(e) => {
+                    tracing::warn!(path = %client.path, "error returned from upstream: {e:?}");
+                    success = false;
+                }
+            }
+        }
+
+        if success {
+            Response::SUCCESS
+        } else {
+            Response::FAILURE
+        }
+    }
+
+    /// Sends `message` to all `clients` concurrently, returning each one's result
+    async fn send_to_each(
+        &self,
+        clients: Vec<Rc<Client>>,
+        message: Request,
+    ) -> Vec<(Rc<Client>, Result<Response, Error>)> {
+        clients
+            .into_iter()
+            .map(|client| {
+                let message = message.clone();
+                async move {
+                    let result = client.send(message, client.timeouts.management).await;
+                    self.record(&client.path, &result);
+                    (client, result)
+                }
             })
+            .collect::<FuturesOrdered<_>>()
+            .collect::<Vec<_>>()
+            .await
+    }
+
+    /// Returns a signature, and the upstream that gave it, if any upstream gives a valid one
+    /// Only asks the upstreams for which `allowed` returns true
+    pub(crate) async fn sign_request(
+        &self,
+        blob: Bytes,
+        data: Bytes,
+        flags: u32,
+        allowed: impl Fn(&str) -> bool,
+    ) -> Option<(Rc<str>, Bytes)> {
+        let fingerprint = packets::fingerprint(&blob);
+        pin!(self
+            .for_each_matching(
+                move |client| allowed(&client.path),
+                move |client| {
+                    let blob = blob.clone();
+                    let data = data.clone();
+                    let fingerprint = fingerprint.clone();
+                    async move {
+                        self.events.emit(EventKind::SignSent {
+                            upstream: client.path.clone(),
+                            fingerprint: fingerprint.clone(),
+                        });
+                        let started = Instant::now();
+                        let (outcome, signature) = match client
+                            .sign_request(blob.clone(), data.clone(), flags)
+                            .await
+                        {
+                            Ok(Some(signature)) => {
+                                match verify::signature(&blob, &data, flags, &signature) {
+                                    Ok(verified) => {
+                                        if !verified {
+                                            tracing::debug!(
+                                                fingerprint,
+                                                "unable to verify this key type or algorithm, returning signature unchecked"
+                                            );
+                                        }
+                                        ("success", Ok(Some(signature)))
+                                    }
+                                    // Failing means the next upstream is tried instead
+                                    Err(e) => (
+                                        "invalid",
+                                        Err(e.wrap_err("upstream returned an invalid signature")),
+                                    ),
+                                }
+                            }
+                            Ok(None) => ("refused", Ok(None)),
+                            Err(e) => ("error", Err(e)),
+                        };
+                        self.metrics.sign(&client.path, outcome);
+                        self.events.emit(EventKind::SignAnswered {
+                            upstream: client.path.clone(),
+                            fingerprint,
+                            outcome: outcome.to_owned(),
+                            elapsed: started.elapsed(),
+                        });
+                        Ok(signature?.map(|signature| (client.path.clone(), signature)))
+                    }
+                },
+            )
             .filter_map(future::ready))
         .next()
         .await
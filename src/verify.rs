@@ -0,0 +1,142 @@
+use bytes::Bytes;
+use eyre::{bail, Error};
+use signature::Verifier as _;
+use ssh_key::{public::KeyData, Certificate, Signature};
+
+use crate::packets::{self, SSH_AGENT_RSA_SHA2_256, SSH_AGENT_RSA_SHA2_512};
+
+/// Checks a host's `session-bind@openssh.com` signature over the session id, the host picks any
+/// algorithm its key supports. Returns whether it was verified, the same as [`signature`].
+#[culpa::throws]
+pub(crate) fn host_signature(blob: &[u8], session_id: &[u8], signature: &Bytes) -> bool {
+    self::signature(
+        blob,
+        session_id,
+        SSH_AGENT_RSA_SHA2_512 | SSH_AGENT_RSA_SHA2_256,
+        signature,
+    )?
+}
+
+/// Checks that an upstream's signature is by the key `blob` over `data`, using the algorithm that
+/// `flags` asked for. Returns whether it was verified, key types and algorithms we can't verify
+/// (`ssh-dss` and SHA-1 `ssh-rsa`) are let through unchecked.
+#[culpa::throws]
+pub(crate) fn signature(blob: &[u8], data: &[u8], flags: u32, signature: &Bytes) -> bool {
+    // Signatures for certificates are made by the key they certify
+    let key = match Certificate::from_bytes(blob) {
+        Ok(certificate) => certificate.public_key().clone(),
+        Err(_) => match ssh_key::PublicKey::from_bytes(blob) {
+            Ok(key) => key.key_data().clone(),
+            Err(_) => return false,
+        },
+    };
+    let Some(algorithm) = packets::key_type(signature) else {
+        bail!("invalid signature encoding");
+    };
+
+    match key {
+        KeyData::Rsa(_) => {
+            // Agents may pick either if both are asked for
+            let expected: &[_] = match (
+                flags & SSH_AGENT_RSA_SHA2_512 != 0,
+                flags & SSH_AGENT_RSA_SHA2_256 != 0,
+            ) {
+                (true, true) => &["rsa-sha2-512", "rsa-sha2-256"],
+                (true, false) => &["rsa-sha2-512"],
+                (false, true) => &["rsa-sha2-256"],
+                (false, false) => &["ssh-rsa"],
+            };
+            if !expected.contains(&algorithm.as_str()) {
+                bail!(
+                    "signature algorithm {algorithm} doesn't match the requested flags {flags:#x}"
+                );
+            }
+            if algorithm == "ssh-rsa" {
+                return false;
+            }
+        }
+        KeyData::Dsa(_) | KeyData::Other(_) => return false,
+        _ if algorithm != key.algorithm().as_str() => {
+            bail!(
+                "signature algorithm {algorithm} doesn't match the {} key",
+                key.algorithm()
+            );
+        }
+        _ => {}
+    }
+
+    let signature = match Signature::try_from(signature.as_ref()) {
+        Ok(signature) => signature,
+        Err(e) => bail!("invalid signature encoding: {e}"),
+    };
+    if let Err(e) = key.verify(data, &signature) {
+        bail!("signature doesn't verify: {e}");
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::{BufMut, Bytes, BytesMut};
+    use signature::Signer as _;
+    use ssh_key::{private::Ed25519Keypair, public::KeyData, PublicKey};
+
+    use crate::packets::SSH_AGENT_RSA_SHA2_256;
+
+    const RSA_KEY: &str = "ssh-rsa AAAAB3NzaC1yc2EAAAADAQABAAAAgQCsORkGexEEh+iatR0Jkf9CHKEkgw2uQG+Y7I6oLBDaVZv0vjtMUVZ0yRe0st4T5LJmLIOB2DvNgr6olA89bDfdw0AhN6Oljhjvefc709tePP/ZiP0puEaP/y4YVdbVhc7d6LXg7Clun2W5fwMDwxH39NzzYVsrzS96bo1rJZ+72w==";
+
+    fn ed25519(seed: u8) -> (Vec<u8>, Ed25519Keypair) {
+        let keypair = Ed25519Keypair::from_seed(&[seed; 32]);
+        let blob = PublicKey::from(KeyData::from(keypair.public))
+            .to_bytes()
+            .unwrap();
+        (blob, keypair)
+    }
+
+    fn sign(keypair: &Ed25519Keypair, data: &[u8]) -> Bytes {
+        Vec::try_from(keypair.sign(data)).unwrap().into()
+    }
+
+    #[test]
+    fn valid() {
+        let (blob, keypair) = ed25519(1);
+        let signature = sign(&keypair, b"data");
+        assert!(super::signature(&blob, b"data", 0, &signature).unwrap());
+    }
+
+    #[test]
+    fn wrong_data() {
+        let (blob, keypair) = ed25519(1);
+        let signature = sign(&keypair, b"other data");
+        let error = super::signature(&blob, b"data", 0, &signature).unwrap_err();
+        assert!(error.to_string().contains("doesn't verify"), "{error}");
+    }
+
+    #[test]
+    fn wrong_key() {
+        let (blob, _) = ed25519(1);
+        let (_, other) = ed25519(2);
+        let signature = sign(&other, b"data");
+        let error = super::signature(&blob, b"data", 0, &signature).unwrap_err();
+        assert!(error.to_string().contains("doesn't verify"), "{error}");
+    }
+
+    #[test]
+    fn wrong_rsa_algorithm() {
+        let blob = PublicKey::from_openssh(RSA_KEY)
+            .unwrap()
+            .to_bytes()
+            .unwrap();
+        let mut signature = BytesMut::new();
+        for field in [&b"ssh-rsa"[..], &[0; 128]] {
+            signature.put_u32(field.len().try_into().unwrap());
+            signature.put_slice(field);
+        }
+        let error = super::signature(&blob, b"data", SSH_AGENT_RSA_SHA2_256, &signature.freeze())
+            .unwrap_err();
+        assert!(
+            error.to_string().contains("ssh-rsa doesn't match"),
+            "{error}"
+        );
+    }
+}